@@ -0,0 +1,12 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS "ix_messages_conversation_id";
+DROP INDEX IF EXISTS "ix_conversation_members_user_id";
+DROP INDEX IF EXISTS "ix_conversation_members_conversation_id";
+DROP INDEX IF EXISTS "ix_conversations_id";
+DELETE FROM "messages" WHERE "conversation_id" IS NOT NULL;
+ALTER TABLE "messages" DROP CONSTRAINT IF EXISTS "ck_messages_target";
+ALTER TABLE "messages" DROP CONSTRAINT IF EXISTS "fk_conversation_id";
+ALTER TABLE "messages" DROP COLUMN IF EXISTS "conversation_id";
+ALTER TABLE "messages" ALTER COLUMN "to_user" SET NOT NULL;
+DROP TABLE IF EXISTS "conversation_members";
+DROP TABLE IF EXISTS "conversations";
@@ -0,0 +1,30 @@
+-- Your SQL goes here
+CREATE TABLE "conversations" (
+    "id" integer NOT NULL GENERATED BY DEFAULT AS IDENTITY,
+    "name" text NOT NULL,
+    "owner_id" integer NOT NULL,
+    "create_time" timestamp without time zone NOT NULL,
+    CONSTRAINT "pk_conversations" PRIMARY KEY ("id"),
+    CONSTRAINT "fk_owner_id" FOREIGN KEY ("owner_id") REFERENCES "users" ("id") ON DELETE CASCADE
+);
+
+CREATE TABLE "conversation_members" (
+    "id" integer NOT NULL GENERATED BY DEFAULT AS IDENTITY,
+    "conversation_id" integer NOT NULL,
+    "user_id" integer NOT NULL,
+    "join_time" timestamp without time zone NOT NULL,
+    CONSTRAINT "pk_conversation_members" PRIMARY KEY ("id"),
+    CONSTRAINT "uq_conversation_members" UNIQUE ("conversation_id", "user_id"),
+    CONSTRAINT "fk_conversation_id" FOREIGN KEY ("conversation_id") REFERENCES "conversations" ("id") ON DELETE CASCADE,
+    CONSTRAINT "fk_user_id" FOREIGN KEY ("user_id") REFERENCES "users" ("id") ON DELETE CASCADE
+);
+
+ALTER TABLE "messages" ALTER COLUMN "to_user" DROP NOT NULL;
+ALTER TABLE "messages" ADD COLUMN "conversation_id" integer NULL DEFAULT (NULL);
+ALTER TABLE "messages" ADD CONSTRAINT "fk_conversation_id" FOREIGN KEY ("conversation_id") REFERENCES "conversations" ("id") ON DELETE CASCADE;
+ALTER TABLE "messages" ADD CONSTRAINT "ck_messages_target" CHECK (("to_user" IS NULL) <> ("conversation_id" IS NULL));
+
+CREATE INDEX "ix_conversations_id" ON "conversations" ("id");
+CREATE INDEX "ix_conversation_members_conversation_id" ON "conversation_members" ("conversation_id");
+CREATE INDEX "ix_conversation_members_user_id" ON "conversation_members" ("user_id");
+CREATE INDEX "ix_messages_conversation_id" ON "messages" ("conversation_id");
@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE "conversation_members" DROP COLUMN IF EXISTS "last_read_id";
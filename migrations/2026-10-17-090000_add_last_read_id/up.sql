@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE "conversation_members" ADD COLUMN "last_read_id" integer NULL DEFAULT (NULL);
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS "ix_attachments_id";
+DROP INDEX IF EXISTS "ix_attachments_message_id";
+DROP TABLE IF EXISTS "attachments";
@@ -0,0 +1,17 @@
+-- Your SQL goes here
+CREATE TABLE "attachments" (
+    "id" integer NOT NULL GENERATED BY DEFAULT AS IDENTITY,
+    "message_id" integer NULL DEFAULT (NULL),
+    "uploader_id" integer NOT NULL,
+    "file_name" text NOT NULL,
+    "content_type" text NOT NULL,
+    "size" bigint NOT NULL,
+    "storage_key" text UNIQUE NOT NULL,
+    "upload_time" timestamp without time zone NOT NULL,
+    CONSTRAINT "pk_attachments" PRIMARY KEY ("id"),
+    CONSTRAINT "fk_message_id" FOREIGN KEY ("message_id") REFERENCES "messages" ("id") ON DELETE CASCADE,
+    CONSTRAINT "fk_uploader_id" FOREIGN KEY ("uploader_id") REFERENCES "users" ("id") ON DELETE CASCADE
+);
+
+CREATE INDEX "ix_attachments_id" ON "attachments" ("id");
+CREATE INDEX "ix_attachments_message_id" ON "attachments" ("message_id");
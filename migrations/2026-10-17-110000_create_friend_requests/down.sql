@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS "ix_friend_requests_from_user";
+DROP INDEX IF EXISTS "ix_friend_requests_to_user";
+DROP TABLE IF EXISTS "friend_requests";
@@ -0,0 +1,14 @@
+-- Your SQL goes here
+CREATE TABLE "friend_requests" (
+    "id" integer NOT NULL GENERATED BY DEFAULT AS IDENTITY,
+    "from_user" integer NOT NULL,
+    "to_user" integer NOT NULL,
+    "create_time" timestamp without time zone NOT NULL,
+    CONSTRAINT "pk_friend_requests" PRIMARY KEY ("id"),
+    CONSTRAINT "uq_friend_requests" UNIQUE ("from_user", "to_user"),
+    CONSTRAINT "fk_from_user_id" FOREIGN KEY ("from_user") REFERENCES "users" ("id") ON DELETE CASCADE,
+    CONSTRAINT "fk_to_user_id" FOREIGN KEY ("to_user") REFERENCES "users" ("id") ON DELETE CASCADE
+);
+
+CREATE INDEX "ix_friend_requests_from_user" ON "friend_requests" ("from_user");
+CREATE INDEX "ix_friend_requests_to_user" ON "friend_requests" ("to_user");
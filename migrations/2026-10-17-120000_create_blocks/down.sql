@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS "ix_blocks_blocked_user_id";
+DROP TABLE IF EXISTS "blocks";
@@ -0,0 +1,13 @@
+-- Your SQL goes here
+CREATE TABLE "blocks" (
+    "id" integer NOT NULL GENERATED BY DEFAULT AS IDENTITY,
+    "user_id" integer NOT NULL,
+    "blocked_user_id" integer NOT NULL,
+    "create_time" timestamp without time zone NOT NULL,
+    CONSTRAINT "pk_blocks" PRIMARY KEY ("id"),
+    CONSTRAINT "uq_blocks" UNIQUE ("user_id", "blocked_user_id"),
+    CONSTRAINT "fk_user_id" FOREIGN KEY ("user_id") REFERENCES "users" ("id") ON DELETE CASCADE,
+    CONSTRAINT "fk_blocked_user_id" FOREIGN KEY ("blocked_user_id") REFERENCES "users" ("id") ON DELETE CASCADE
+);
+
+CREATE INDEX "ix_blocks_blocked_user_id" ON "blocks" ("blocked_user_id");
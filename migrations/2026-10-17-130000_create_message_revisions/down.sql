@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS "ix_message_revisions_message_id";
+DROP TABLE IF EXISTS "message_revisions";
+ALTER TABLE "messages" DROP COLUMN IF EXISTS "edited_time";
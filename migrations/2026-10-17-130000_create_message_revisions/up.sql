@@ -0,0 +1,13 @@
+-- Your SQL goes here
+ALTER TABLE "messages" ADD COLUMN "edited_time" timestamp without time zone NULL DEFAULT (NULL);
+
+CREATE TABLE "message_revisions" (
+    "id" integer NOT NULL GENERATED BY DEFAULT AS IDENTITY,
+    "message_id" integer NOT NULL,
+    "message" text NOT NULL,
+    "create_time" timestamp without time zone NOT NULL,
+    CONSTRAINT "pk_message_revisions" PRIMARY KEY ("id"),
+    CONSTRAINT "fk_message_id" FOREIGN KEY ("message_id") REFERENCES "messages" ("id") ON DELETE CASCADE
+);
+
+CREATE INDEX "ix_message_revisions_message_id" ON "message_revisions" ("message_id");
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE IF EXISTS "message_deletions";
+ALTER TABLE "messages" DROP COLUMN IF EXISTS "recalled_time";
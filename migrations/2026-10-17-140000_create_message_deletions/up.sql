@@ -0,0 +1,13 @@
+-- Your SQL goes here
+ALTER TABLE "messages" ADD COLUMN "recalled_time" timestamp without time zone NULL DEFAULT (NULL);
+
+CREATE TABLE "message_deletions" (
+    "id" integer NOT NULL GENERATED BY DEFAULT AS IDENTITY,
+    "message_id" integer NOT NULL,
+    "user_id" integer NOT NULL,
+    "delete_time" timestamp without time zone NOT NULL,
+    CONSTRAINT "pk_message_deletions" PRIMARY KEY ("id"),
+    CONSTRAINT "uq_message_deletions" UNIQUE ("user_id", "message_id"),
+    CONSTRAINT "fk_message_id" FOREIGN KEY ("message_id") REFERENCES "messages" ("id") ON DELETE CASCADE,
+    CONSTRAINT "fk_user_id" FOREIGN KEY ("user_id") REFERENCES "users" ("id") ON DELETE CASCADE
+);
@@ -0,0 +1,12 @@
+-- Your SQL goes here
+CREATE TABLE "message_reactions" (
+    "id" integer NOT NULL GENERATED BY DEFAULT AS IDENTITY,
+    "message_id" integer NOT NULL,
+    "user_id" integer NOT NULL,
+    "emoji" text NOT NULL,
+    "create_time" timestamp without time zone NOT NULL,
+    CONSTRAINT "pk_message_reactions" PRIMARY KEY ("id"),
+    CONSTRAINT "uq_message_reactions" UNIQUE ("message_id", "user_id", "emoji"),
+    CONSTRAINT "fk_message_id" FOREIGN KEY ("message_id") REFERENCES "messages" ("id") ON DELETE CASCADE,
+    CONSTRAINT "fk_user_id" FOREIGN KEY ("user_id") REFERENCES "users" ("id") ON DELETE CASCADE
+);
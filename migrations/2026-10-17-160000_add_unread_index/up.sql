@@ -0,0 +1,2 @@
+-- Your SQL goes here
+CREATE INDEX "ix_messages_unread" ON "messages" ("to_user", "from_user") WHERE "read_time" IS NULL;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS "ix_messages_search_vector";
+ALTER TABLE "messages" DROP COLUMN IF EXISTS "search_vector";
@@ -0,0 +1,6 @@
+-- Your SQL goes here
+ALTER TABLE "messages" ADD COLUMN "search_vector" tsvector GENERATED ALWAYS AS (
+    to_tsvector('simple', CASE WHEN "message_type" = 0 THEN "message" ELSE '' END)
+) STORED;
+
+CREATE INDEX "ix_messages_search_vector" ON "messages" USING GIN ("search_vector");
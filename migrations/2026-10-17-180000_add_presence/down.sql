@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE "users" DROP COLUMN IF EXISTS "hide_presence";
+ALTER TABLE "users" DROP COLUMN IF EXISTS "last_seen";
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE "users" ADD COLUMN "last_seen" timestamp without time zone NULL DEFAULT (NULL);
+ALTER TABLE "users" ADD COLUMN "hide_presence" boolean NOT NULL DEFAULT FALSE;
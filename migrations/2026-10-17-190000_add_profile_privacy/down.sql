@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE "users" DROP COLUMN IF EXISTS "age_visibility";
+ALTER TABLE "users" DROP COLUMN IF EXISTS "location_visibility";
+ALTER TABLE "users" DROP COLUMN IF EXISTS "phone_visibility";
+ALTER TABLE "users" DROP COLUMN IF EXISTS "email_visibility";
@@ -0,0 +1,6 @@
+-- Your SQL goes here
+-- 0: everyone, 1: friends, 2: nobody
+ALTER TABLE "users" ADD COLUMN "email_visibility" integer NOT NULL DEFAULT 1;
+ALTER TABLE "users" ADD COLUMN "phone_visibility" integer NOT NULL DEFAULT 1;
+ALTER TABLE "users" ADD COLUMN "location_visibility" integer NOT NULL DEFAULT 0;
+ALTER TABLE "users" ADD COLUMN "age_visibility" integer NOT NULL DEFAULT 0;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS "ix_refresh_tokens_user_id";
+DROP TABLE IF EXISTS "refresh_tokens";
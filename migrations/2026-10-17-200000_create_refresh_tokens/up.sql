@@ -0,0 +1,13 @@
+-- Your SQL goes here
+CREATE TABLE "refresh_tokens" (
+    "id" integer NOT NULL GENERATED BY DEFAULT AS IDENTITY,
+    "user_id" integer NOT NULL,
+    "token_hash" text NOT NULL,
+    "create_time" timestamp without time zone NOT NULL,
+    "expire_time" timestamp without time zone NOT NULL,
+    CONSTRAINT "pk_refresh_tokens" PRIMARY KEY ("id"),
+    CONSTRAINT "uq_refresh_tokens_token_hash" UNIQUE ("token_hash"),
+    CONSTRAINT "fk_user_id" FOREIGN KEY ("user_id") REFERENCES "users" ("id") ON DELETE CASCADE
+);
+
+CREATE INDEX "ix_refresh_tokens_user_id" ON "refresh_tokens" ("user_id");
@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS "ix_refresh_tokens_session_id";
+ALTER TABLE "refresh_tokens" DROP COLUMN IF EXISTS "session_id";
+DROP INDEX IF EXISTS "ix_sessions_user_id";
+DROP TABLE IF EXISTS "sessions";
@@ -0,0 +1,20 @@
+-- Your SQL goes here
+CREATE TABLE "sessions" (
+    "id" integer NOT NULL GENERATED BY DEFAULT AS IDENTITY,
+    "user_id" integer NOT NULL,
+    "device_name" text NULL DEFAULT (NULL),
+    "ip_address" text NULL DEFAULT (NULL),
+    "user_agent" text NULL DEFAULT (NULL),
+    "create_time" timestamp without time zone NOT NULL,
+    "last_used_time" timestamp without time zone NOT NULL,
+    CONSTRAINT "pk_sessions" PRIMARY KEY ("id"),
+    CONSTRAINT "fk_user_id" FOREIGN KEY ("user_id") REFERENCES "users" ("id") ON DELETE CASCADE
+);
+
+CREATE INDEX "ix_sessions_user_id" ON "sessions" ("user_id");
+
+-- Refresh tokens issued before sessions existed can't be tied to one.
+DELETE FROM "refresh_tokens";
+ALTER TABLE "refresh_tokens" ADD COLUMN "session_id" integer NOT NULL;
+ALTER TABLE "refresh_tokens" ADD CONSTRAINT "fk_session_id" FOREIGN KEY ("session_id") REFERENCES "sessions" ("id") ON DELETE CASCADE;
+CREATE INDEX "ix_refresh_tokens_session_id" ON "refresh_tokens" ("session_id");
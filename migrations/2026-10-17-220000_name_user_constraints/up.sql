@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE "users" RENAME CONSTRAINT "users_username_key" TO "uq_users_username";
+ALTER TABLE "users" RENAME CONSTRAINT "users_email_key" TO "uq_users_email";
@@ -0,0 +1,228 @@
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+use actix_multipart::Multipart;
+use actix_web::{
+    http::header::{ContentDisposition, DispositionParam, DispositionType},
+    web, HttpResponse, Responder,
+};
+use chrono::Utc;
+use diesel::prelude::*;
+use futures::StreamExt;
+use rand::Rng;
+
+use crate::{
+    api::{auth::AuthUser, error::AppError},
+    config::UploadConfig,
+    model::{attachment::AttachmentInfo, ResultModel},
+    schema::{self, NewAttachment},
+    storage::Storage,
+    DbPool,
+};
+
+const ALLOWED_CONTENT_TYPES: &[&str] = &[
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "audio/mpeg",
+    "audio/ogg",
+    "video/mp4",
+    "application/pdf",
+    "application/zip",
+    "text/plain",
+];
+
+pub struct UploadedFile {
+    pub file_name: String,
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+/// Reads the `file` field of a multipart upload, enforcing the size and content
+/// type limits.
+pub async fn read_upload(
+    payload: &mut Multipart,
+    max_size: usize,
+    allowed_content_types: &[&str],
+) -> Result<UploadedFile, AppError> {
+    while let Some(item) = payload.next().await {
+        let mut field = item.map_err(|e| AppError::BadRequest(e.to_string()))?;
+        let content_disposition = match field.content_disposition() {
+            Some(content_disposition) if content_disposition.get_name() == Some("file") => {
+                content_disposition
+            }
+            _ => continue,
+        };
+        let file_name = content_disposition
+            .get_filename()
+            .and_then(|name| Path::new(name).file_name())
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| "file".to_string());
+        let content_type = field.content_type().essence_str().to_string();
+        if !allowed_content_types.contains(&content_type.as_str()) {
+            return Err(AppError::UnsupportedMediaType(
+                "Unsupported file type.".to_string(),
+            ));
+        }
+
+        let mut data = Vec::<u8>::new();
+        while let Some(chunk) = field.next().await {
+            let bytes = chunk.map_err(|e| AppError::BadRequest(e.to_string()))?;
+            if data.len() + bytes.len() > max_size {
+                return Err(AppError::PayloadTooLarge("File is too large.".to_string()));
+            }
+            data.extend_from_slice(&bytes);
+        }
+        return Ok(UploadedFile {
+            file_name,
+            content_type,
+            data,
+        });
+    }
+    Err(AppError::BadRequest("No file uploaded.".to_string()))
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.route("/upload", web::post().to(upload));
+    cfg.route("/{attachment_id}", web::get().to(download));
+}
+
+pub fn to_attachment_info(attachment: &schema::Attachment) -> AttachmentInfo {
+    AttachmentInfo {
+        id: attachment.id,
+        file_name: attachment.file_name.clone(),
+        content_type: attachment.content_type.clone(),
+        size: attachment.size,
+    }
+}
+
+/// Loads the attachments of the given messages, keyed by message id.
+pub fn load_attachments(
+    conn: &PgConnection,
+    message_ids: &[i32],
+) -> QueryResult<HashMap<i32, Vec<AttachmentInfo>>> {
+    let mut result = HashMap::<i32, Vec<AttachmentInfo>>::new();
+    if message_ids.is_empty() {
+        return Ok(result);
+    }
+    for item in schema::attachments::dsl::attachments
+        .filter(schema::attachments::dsl::message_id.eq_any(message_ids))
+        .order(schema::attachments::dsl::id.asc())
+        .load::<schema::Attachment>(conn)?
+    {
+        if let Some(message_id) = item.message_id {
+            result
+                .entry(message_id)
+                .or_default()
+                .push(to_attachment_info(&item));
+        }
+    }
+    Ok(result)
+}
+
+/// The uploader can always access an attachment; once it is sent, so can every
+/// participant of the message's conversation.
+fn can_access(
+    conn: &PgConnection,
+    self_user_id: i32,
+    attachment: &schema::Attachment,
+) -> QueryResult<bool> {
+    if attachment.uploader_id == self_user_id {
+        return Ok(true);
+    }
+    let message_id = match attachment.message_id {
+        Some(message_id) => message_id,
+        None => return Ok(false),
+    };
+    let target = schema::messages::dsl::messages
+        .filter(schema::messages::dsl::id.eq(&message_id))
+        .first::<schema::Message>(conn)?;
+    match target.conversation_id {
+        Some(conversation_id) => schema::conversation_members::dsl::conversation_members
+            .filter(
+                schema::conversation_members::dsl::conversation_id
+                    .eq(&conversation_id)
+                    .and(schema::conversation_members::dsl::user_id.eq(&self_user_id)),
+            )
+            .count()
+            .get_result::<i64>(conn)
+            .map(|count| count > 0),
+        None => Ok(target.from_user == self_user_id || target.to_user == Some(self_user_id)),
+    }
+}
+
+pub async fn upload(
+    mut payload: Multipart,
+    auth: AuthUser,
+    pool: web::Data<DbPool>,
+    storage: web::Data<Arc<dyn Storage>>,
+    uploads: web::Data<UploadConfig>,
+) -> Result<impl Responder, AppError> {
+    let self_user_id = auth.user_id;
+    let upload = read_upload(
+        &mut payload,
+        uploads.max_attachment_size,
+        ALLOWED_CONTENT_TYPES,
+    )
+    .await?;
+    let conn = pool.get()?;
+    let storage = storage.get_ref().clone();
+    let storage_key = format!("{:032x}", rand::thread_rng().gen::<u128>());
+    let attachment = web::block(move || -> Result<_, AppError> {
+        storage.save(&storage_key, &upload.data)?;
+        diesel::insert_into(schema::attachments::dsl::attachments)
+            .values(&NewAttachment {
+                uploader_id: self_user_id,
+                file_name: &upload.file_name,
+                content_type: &upload.content_type,
+                size: upload.data.len() as i64,
+                storage_key: &storage_key,
+                upload_time: Utc::now().naive_utc(),
+            })
+            .get_result::<schema::Attachment>(&conn)
+            .map_err(|e| {
+                let _ = storage.remove(&storage_key);
+                e.into()
+            })
+    })
+    .await?;
+    Ok(ResultModel::ok(to_attachment_info(&attachment)))
+}
+
+pub async fn download(
+    web::Path(attachment_id): web::Path<i32>,
+    auth: AuthUser,
+    pool: web::Data<DbPool>,
+    storage: web::Data<Arc<dyn Storage>>,
+) -> Result<HttpResponse, AppError> {
+    let conn = pool.get()?;
+    let self_user_id = auth.user_id;
+    let storage = storage.get_ref().clone();
+    let (attachment, data) = web::block(move || -> Result<_, AppError> {
+        let not_found = || AppError::NotFound("Attachment doesn't exists.".to_string());
+        let attachment = schema::attachments::dsl::attachments
+            .filter(schema::attachments::dsl::id.eq(&attachment_id))
+            .first::<schema::Attachment>(&conn)
+            .optional()?
+            .ok_or_else(not_found)?;
+        if !can_access(&conn, self_user_id, &attachment)? {
+            return Err(not_found());
+        }
+        let data = storage.load(&attachment.storage_key)?;
+        Ok((attachment, data))
+    })
+    .await?;
+    Ok(HttpResponse::Ok()
+        .content_type(attachment.content_type.as_str())
+        .set(ContentDisposition {
+            disposition: if attachment.content_type.starts_with("image/") {
+                DispositionType::Inline
+            } else {
+                DispositionType::Attachment
+            },
+            parameters: vec![DispositionParam::Filename(attachment.file_name)],
+        })
+        .header("X-Content-Type-Options", "nosniff")
+        .header("Cache-Control", "private, max-age=86400")
+        .body(data))
+}
@@ -0,0 +1,484 @@
+use actix_identity::RequestIdentity;
+use actix_web::{dev::Payload, http::header, web, FromRequest, HttpRequest};
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use futures::future::LocalBoxFuture;
+use hmac::{Hmac, Mac, NewMac};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::net::SocketAddr;
+
+use crate::{
+    api::error::AppError,
+    model::user::TokenModel,
+    schema::{self, NewRefreshToken, NewSession},
+    DbPool,
+};
+
+/// Base64 of `{"alg":"HS256","typ":"JWT"}`, the header of every access token.
+const TOKEN_HEADER: &str = "eyJhbGciOiJIUzI1NiIsInR5cCI6IkpXVCJ9";
+/// Seconds between updates of a session's last use, so busy clients don't write on
+/// every request.
+const SESSION_TOUCH_INTERVAL: i64 = 60;
+
+#[derive(Clone)]
+pub struct TokenSettings {
+    /// Signs access tokens; the same secret as the identity cookie.
+    pub key: Vec<u8>,
+    pub access_token_lifetime: Duration,
+    /// Also how long a login session may go unused before it ends.
+    pub refresh_token_lifetime: Duration,
+}
+
+#[derive(Serialize, Deserialize)]
+struct AccessClaims {
+    sub: String,
+    /// The login session the token belongs to.
+    sid: i32,
+    iat: i64,
+    exp: i64,
+}
+
+/// Where a login came from, as shown in the session list.
+pub struct DeviceInfo {
+    pub device_name: Option<String>,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl DeviceInfo {
+    pub fn from_request(req: &HttpRequest, device_name: Option<String>) -> Self {
+        Self {
+            device_name: device_name.filter(|name| !name.trim().is_empty()),
+            ip_address: client_ip(req),
+            user_agent: req
+                .headers()
+                .get(header::USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_string()),
+        }
+    }
+}
+
+fn client_ip(req: &HttpRequest) -> Option<String> {
+    req.connection_info().realip_remote_addr().map(|addr| {
+        addr.parse::<SocketAddr>()
+            .map(|addr| addr.ip().to_string())
+            .unwrap_or_else(|_| addr.to_string())
+    })
+}
+
+/// The value of the identity cookie, which names the login session as well as the
+/// user.
+pub fn cookie_identity(user_id: i32, session_id: i32) -> String {
+    format!("{}:{}", user_id, session_id)
+}
+
+/// The user and login session an identity cookie claims. Cookies from before login
+/// sessions existed only hold a user id and are rejected.
+fn cookie_session(value: &str) -> Option<(i32, i32)> {
+    let separator = value.find(':')?;
+    Some((
+        value[..separator].parse().ok()?,
+        value[separator + 1..].parse().ok()?,
+    ))
+}
+
+fn encode(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+fn mac(key: &[u8], data: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC takes keys of any length.");
+    mac.update(data.as_bytes());
+    mac
+}
+
+/// Issues a JWT signed with HS256 naming `user_id` as its subject.
+fn issue_access_token(settings: &TokenSettings, user_id: i32, session_id: i32) -> String {
+    let now = Utc::now().timestamp();
+    let claims = AccessClaims {
+        sub: user_id.to_string(),
+        sid: session_id,
+        iat: now,
+        exp: now + settings.access_token_lifetime.num_seconds(),
+    };
+    let signed = format!(
+        "{}.{}",
+        TOKEN_HEADER,
+        encode(&serde_json::to_vec(&claims).unwrap_or_default())
+    );
+    let signature = encode(&mac(&settings.key, &signed).finalize().into_bytes());
+    format!("{}.{}", signed, signature)
+}
+
+/// Returns the user and login session an access token was issued to, if it is genuine
+/// and unexpired. Whether the session is still live is up to the caller.
+pub fn verify_access_token(settings: &TokenSettings, token: &str) -> Option<(i32, i32)> {
+    let signature_start = token.rfind('.')?;
+    let (signed, signature) = (&token[..signature_start], &token[signature_start + 1..]);
+    let payload = signed.strip_prefix(TOKEN_HEADER)?.strip_prefix('.')?;
+    let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD).ok()?;
+    mac(&settings.key, signed).verify(&signature).ok()?;
+    let claims = serde_json::from_slice::<AccessClaims>(
+        &base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?,
+    )
+    .ok()?;
+    if claims.exp <= Utc::now().timestamp() {
+        return None;
+    }
+    Some((claims.sub.parse().ok()?, claims.sid))
+}
+
+/// Refresh tokens are only stored hashed, so a leaked table can't be replayed.
+fn hash_refresh_token(token: &str) -> String {
+    encode(&Sha256::digest(token.as_bytes()))
+}
+
+/// Starts a login session for `user_id`, ending any of theirs left unused for longer
+/// than a refresh token lives.
+pub fn create_session(
+    conn: &PgConnection,
+    settings: &TokenSettings,
+    user_id: i32,
+    device: &DeviceInfo,
+) -> QueryResult<i32> {
+    let now = Utc::now().naive_utc();
+    diesel::delete(
+        schema::sessions::dsl::sessions.filter(
+            schema::sessions::dsl::user_id.eq(&user_id).and(
+                schema::sessions::dsl::last_used_time.le(now - settings.refresh_token_lifetime),
+            ),
+        ),
+    )
+    .execute(conn)?;
+    diesel::insert_into(schema::sessions::table)
+        .values(NewSession {
+            user_id,
+            device_name: device.device_name.as_deref(),
+            ip_address: device.ip_address.as_deref(),
+            user_agent: device.user_agent.as_deref(),
+            create_time: now,
+            last_used_time: now,
+        })
+        .returning(schema::sessions::dsl::id)
+        .get_result(conn)
+}
+
+/// Whether the login session is still live, recording that it was just used.
+fn touch_session(
+    conn: &PgConnection,
+    settings: &TokenSettings,
+    user_id: i32,
+    session_id: i32,
+    ip_address: Option<&str>,
+) -> QueryResult<bool> {
+    let now = Utc::now().naive_utc();
+    let last_used = schema::sessions::dsl::sessions
+        .filter(
+            schema::sessions::dsl::id
+                .eq(&session_id)
+                .and(schema::sessions::dsl::user_id.eq(&user_id)),
+        )
+        .select(schema::sessions::dsl::last_used_time)
+        .first::<NaiveDateTime>(conn)
+        .optional()?;
+    match last_used {
+        Some(last_used) if last_used > now - settings.refresh_token_lifetime => {
+            if last_used <= now - Duration::seconds(SESSION_TOUCH_INTERVAL) {
+                diesel::update(
+                    schema::sessions::dsl::sessions
+                        .filter(schema::sessions::dsl::id.eq(&session_id)),
+                )
+                .set((
+                    schema::sessions::dsl::last_used_time.eq(now),
+                    schema::sessions::dsl::ip_address.eq(ip_address),
+                ))
+                .execute(conn)?;
+            }
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Issues an access token and a new refresh token for the login session
+/// `session_id` of `user_id`, dropping any of their refresh tokens that have
+/// expired.
+pub fn issue_tokens(
+    conn: &PgConnection,
+    settings: &TokenSettings,
+    user_id: i32,
+    session_id: i32,
+) -> QueryResult<TokenModel> {
+    let now = Utc::now().naive_utc();
+    diesel::delete(
+        schema::refresh_tokens::dsl::refresh_tokens.filter(
+            schema::refresh_tokens::dsl::user_id
+                .eq(&user_id)
+                .and(schema::refresh_tokens::dsl::expire_time.le(&now)),
+        ),
+    )
+    .execute(conn)?;
+    let refresh_token = encode(&rand::thread_rng().gen::<[u8; 32]>());
+    diesel::insert_into(schema::refresh_tokens::table)
+        .values(NewRefreshToken {
+            user_id,
+            token_hash: &hash_refresh_token(&refresh_token),
+            create_time: now,
+            expire_time: now + settings.refresh_token_lifetime,
+            session_id,
+        })
+        .execute(conn)?;
+    Ok(TokenModel {
+        access_token: issue_access_token(settings, user_id, session_id),
+        token_type: "Bearer".to_string(),
+        expires_in: settings.access_token_lifetime.num_seconds(),
+        refresh_token,
+    })
+}
+
+/// Trades a refresh token for new tokens. Each refresh token works once; `None`
+/// means it was unknown, already used or expired, or its session has ended.
+pub fn refresh_tokens(
+    conn: &PgConnection,
+    settings: &TokenSettings,
+    refresh_token: &str,
+    ip_address: Option<&str>,
+) -> QueryResult<Option<TokenModel>> {
+    conn.transaction(|| {
+        let session = diesel::delete(
+            schema::refresh_tokens::dsl::refresh_tokens.filter(
+                schema::refresh_tokens::dsl::token_hash
+                    .eq(hash_refresh_token(refresh_token))
+                    .and(schema::refresh_tokens::dsl::expire_time.gt(Utc::now().naive_utc())),
+            ),
+        )
+        .returning((
+            schema::refresh_tokens::dsl::user_id,
+            schema::refresh_tokens::dsl::session_id,
+        ))
+        .get_result::<(i32, i32)>(conn)
+        .optional()?;
+        match session {
+            Some((user_id, session_id))
+                if touch_session(conn, settings, user_id, session_id, ip_address)? =>
+            {
+                issue_tokens(conn, settings, user_id, session_id).map(Some)
+            }
+            _ => Ok(None),
+        }
+    })
+}
+
+/// The user and login session a refresh token belongs to.
+pub fn refresh_token_session(
+    conn: &PgConnection,
+    refresh_token: &str,
+) -> QueryResult<Option<(i32, i32)>> {
+    schema::refresh_tokens::dsl::refresh_tokens
+        .filter(schema::refresh_tokens::dsl::token_hash.eq(hash_refresh_token(refresh_token)))
+        .select((
+            schema::refresh_tokens::dsl::user_id,
+            schema::refresh_tokens::dsl::session_id,
+        ))
+        .first(conn)
+        .optional()
+}
+
+/// The signed-in caller, from an `Authorization: Bearer` access token or else the
+/// identity cookie. Extracting it fails with 401 unless the login session it names is
+/// live; take `Option<AuthUser>` where signing in is optional.
+#[derive(Clone, Copy, Debug)]
+pub struct AuthUser {
+    pub user_id: i32,
+    /// The login session the request was made with.
+    pub session_id: i32,
+}
+
+impl AuthUser {
+    /// Checks the user and login session a credential claims against the sessions
+    /// table; `None` if it was revoked or has lapsed.
+    pub async fn resolve(
+        req: &HttpRequest,
+        claimed: Option<(i32, i32)>,
+    ) -> Result<Option<Self>, AppError> {
+        let (user_id, session_id) = match claimed {
+            Some(claimed) => claimed,
+            None => return Ok(None),
+        };
+        let (pool, settings) = match (
+            req.app_data::<web::Data<DbPool>>(),
+            req.app_data::<web::Data<TokenSettings>>(),
+        ) {
+            (Some(pool), Some(settings)) => (pool.clone(), settings.clone()),
+            _ => return Ok(None),
+        };
+        let ip_address = client_ip(req);
+        let live = web::block(move || -> Result<_, AppError> {
+            let conn = pool.get()?;
+            Ok(touch_session(
+                &conn,
+                &settings,
+                user_id,
+                session_id,
+                ip_address.as_deref(),
+            )?)
+        })
+        .await?;
+        Ok(if live {
+            Some(AuthUser {
+                user_id,
+                session_id,
+            })
+        } else {
+            None
+        })
+    }
+}
+
+/// The access token of an `Authorization: Bearer` header, if the request has one.
+fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+/// The user and login session a request claims: a bearer token, else `access_token`
+/// taken from elsewhere in the request, else the identity cookie. A bad token is
+/// rejected outright instead of falling back to the cookie.
+fn claimed_session(req: &HttpRequest, access_token: Option<&str>) -> Option<(i32, i32)> {
+    match bearer_token(req).or(access_token) {
+        Some(token) => req
+            .app_data::<web::Data<TokenSettings>>()
+            .and_then(|settings| verify_access_token(settings, token)),
+        None => req
+            .get_identity()
+            .and_then(|identity| cookie_session(&identity)),
+    }
+}
+
+impl AuthUser {
+    /// Authenticates a WebSocket upgrade. Browsers can't set headers on it, so the
+    /// access token may also come from the query string.
+    pub async fn from_upgrade(
+        req: &HttpRequest,
+        access_token: Option<&str>,
+    ) -> Result<Self, AppError> {
+        AuthUser::resolve(req, claimed_session(req, access_token))
+            .await?
+            .ok_or_else(AppError::not_logged_in)
+    }
+}
+
+impl FromRequest for AuthUser {
+    type Error = AppError;
+    type Future = LocalBoxFuture<'static, Result<Self, AppError>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let claimed = claimed_session(req, None);
+        let req = req.clone();
+        Box::pin(async move {
+            AuthUser::resolve(&req, claimed)
+                .await?
+                .ok_or_else(AppError::not_logged_in)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> TokenSettings {
+        TokenSettings {
+            key: b"test key".to_vec(),
+            access_token_lifetime: Duration::minutes(15),
+            refresh_token_lifetime: Duration::days(30),
+        }
+    }
+
+    /// Signs `payload` after `header` the way `issue_access_token` does.
+    fn sign(settings: &TokenSettings, header: &str, payload: &str) -> String {
+        let signed = format!("{}.{}", header, payload);
+        let signature = encode(&mac(&settings.key, &signed).finalize().into_bytes());
+        format!("{}.{}", signed, signature)
+    }
+
+    fn payload(user_id: i32, session_id: i32, exp: i64) -> String {
+        let claims = AccessClaims {
+            sub: user_id.to_string(),
+            sid: session_id,
+            iat: exp - 900,
+            exp,
+        };
+        encode(&serde_json::to_vec(&claims).unwrap())
+    }
+
+    #[test]
+    fn valid_token_round_trips() {
+        let settings = settings();
+        let token = issue_access_token(&settings, 7, 42);
+        assert_eq!(verify_access_token(&settings, &token), Some((7, 42)));
+    }
+
+    #[test]
+    fn tampered_payload_is_rejected() {
+        let settings = settings();
+        let token = issue_access_token(&settings, 7, 42);
+        let parts: Vec<&str> = token.split('.').collect();
+        let exp = Utc::now().timestamp() + 900;
+        let forged = format!("{}.{}.{}", parts[0], payload(8, 42, exp), parts[2]);
+        assert_eq!(verify_access_token(&settings, &forged), None);
+    }
+
+    #[test]
+    fn tampered_signature_is_rejected() {
+        let settings = settings();
+        let token = issue_access_token(&settings, 7, 42);
+        let signature_start = token.rfind('.').unwrap() + 1;
+        let mut signature =
+            base64::decode_config(&token[signature_start..], base64::URL_SAFE_NO_PAD).unwrap();
+        signature[0] ^= 1;
+        let forged = format!("{}{}", &token[..signature_start], encode(&signature));
+        assert_eq!(verify_access_token(&settings, &forged), None);
+    }
+
+    #[test]
+    fn token_signed_with_another_key_is_rejected() {
+        let settings = settings();
+        let other = TokenSettings {
+            key: b"other key".to_vec(),
+            ..settings.clone()
+        };
+        let token = issue_access_token(&other, 7, 42);
+        assert_eq!(verify_access_token(&settings, &token), None);
+    }
+
+    #[test]
+    fn wrong_header_is_rejected() {
+        let settings = settings();
+        let header = encode(br#"{"alg":"none","typ":"JWT"}"#);
+        let token = sign(
+            &settings,
+            &header,
+            &payload(7, 42, Utc::now().timestamp() + 900),
+        );
+        assert_eq!(verify_access_token(&settings, &token), None);
+    }
+
+    #[test]
+    fn expired_token_is_rejected() {
+        let settings = settings();
+        let token = sign(
+            &settings,
+            TOKEN_HEADER,
+            &payload(7, 42, Utc::now().timestamp() - 1),
+        );
+        assert_eq!(verify_access_token(&settings, &token), None);
+    }
+}
@@ -0,0 +1,180 @@
+use actix_web::{error::BlockingError, http::StatusCode, HttpResponse, ResponseError};
+use diesel::{r2d2::PoolError, result::DatabaseErrorKind};
+use std::{fmt, io};
+
+use crate::model::{ErrorCode, ResultModel};
+
+/// Why a request failed. Every kind answers with a fixed status, which is also the
+/// `code` of the `ResultModel` it is rendered as.
+#[derive(Debug)]
+pub enum AppError {
+    /// The request is malformed or breaks a rule.
+    BadRequest(String),
+    WeakPassword(String),
+    NotLoggedIn,
+    /// A password or refresh token was rejected.
+    Unauthorized(String),
+    Forbidden(String),
+    NotFound(String),
+    UsernameTaken,
+    EmailTaken,
+    /// A unique constraint without a dedicated kind was violated.
+    Conflict(String),
+    PayloadTooLarge(String),
+    UnsupportedMediaType(String),
+    Database(diesel::result::Error),
+    /// No database connection became free in time.
+    Pool(PoolError),
+    Password(bcrypt::BcryptError),
+    /// Anything else that failed on the server, e.g. file storage.
+    Internal(String),
+    Canceled,
+}
+
+impl AppError {
+    pub fn not_logged_in() -> Self {
+        AppError::NotLoggedIn
+    }
+
+    pub fn error_code(&self) -> ErrorCode {
+        match self {
+            AppError::BadRequest(_) => ErrorCode::BadRequest,
+            AppError::WeakPassword(_) => ErrorCode::WeakPassword,
+            AppError::NotLoggedIn => ErrorCode::NotLoggedIn,
+            AppError::Unauthorized(_) => ErrorCode::InvalidCredentials,
+            AppError::Forbidden(_) => ErrorCode::Forbidden,
+            AppError::NotFound(_) | AppError::Database(diesel::result::Error::NotFound) => {
+                ErrorCode::NotFound
+            }
+            AppError::UsernameTaken => ErrorCode::UsernameTaken,
+            AppError::EmailTaken => ErrorCode::EmailTaken,
+            AppError::Conflict(_) => ErrorCode::Conflict,
+            AppError::PayloadTooLarge(_) => ErrorCode::PayloadTooLarge,
+            AppError::UnsupportedMediaType(_) => ErrorCode::UnsupportedMediaType,
+            AppError::Pool(_) => ErrorCode::Unavailable,
+            AppError::Database(_)
+            | AppError::Password(_)
+            | AppError::Internal(_)
+            | AppError::Canceled => ErrorCode::Internal,
+        }
+    }
+
+    /// The message shown to clients. Server-side failures only get a generic one;
+    /// their details are left to `log`.
+    pub fn client_message(&self) -> String {
+        match self.error_code() {
+            ErrorCode::Internal => "Internal server error.".to_string(),
+            ErrorCode::Unavailable => "Service temporarily unavailable.".to_string(),
+            ErrorCode::NotFound if matches!(self, AppError::Database(_)) => {
+                "Not found.".to_string()
+            }
+            _ => self.to_string(),
+        }
+    }
+
+    /// Logs the details of server-side failures, which clients don't get to see.
+    pub fn log(&self) {
+        match self.error_code() {
+            ErrorCode::Internal => log::error!("{}", self),
+            ErrorCode::Unavailable => log::warn!("{}", self),
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::BadRequest(message)
+            | AppError::WeakPassword(message)
+            | AppError::Unauthorized(message)
+            | AppError::Forbidden(message)
+            | AppError::NotFound(message)
+            | AppError::Conflict(message)
+            | AppError::PayloadTooLarge(message)
+            | AppError::UnsupportedMediaType(message)
+            | AppError::Internal(message) => f.write_str(message),
+            AppError::NotLoggedIn => f.write_str("Not logged in."),
+            AppError::UsernameTaken => f.write_str("Username is already taken."),
+            AppError::EmailTaken => f.write_str("Email is already registered."),
+            AppError::Database(e) => e.fmt(f),
+            AppError::Pool(e) => e.fmt(f),
+            AppError::Password(e) => e.fmt(f),
+            AppError::Canceled => f.write_str("Operation has been cancelled."),
+        }
+    }
+}
+
+impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        match self.error_code() {
+            ErrorCode::BadRequest | ErrorCode::WeakPassword => StatusCode::BAD_REQUEST,
+            ErrorCode::NotLoggedIn | ErrorCode::InvalidCredentials => StatusCode::UNAUTHORIZED,
+            ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::UsernameTaken | ErrorCode::EmailTaken | ErrorCode::Conflict => {
+                StatusCode::CONFLICT
+            }
+            ErrorCode::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ErrorCode::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        self.log();
+        HttpResponse::build(self.status_code()).json(ResultModel::<()> {
+            success: false,
+            code: self.status_code().as_u16(),
+            data: None,
+            message: Some(self.client_message()),
+            error_code: Some(self.error_code()),
+        })
+    }
+}
+
+impl From<diesel::result::Error> for AppError {
+    fn from(e: diesel::result::Error) -> Self {
+        match &e {
+            diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, info) => {
+                match info.constraint_name() {
+                    Some("uq_users_username") => AppError::UsernameTaken,
+                    Some("uq_users_email") => AppError::EmailTaken,
+                    _ => AppError::Conflict("Already exists.".to_string()),
+                }
+            }
+            diesel::result::Error::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, _) => {
+                AppError::NotFound("Referenced item doesn't exists.".to_string())
+            }
+            _ => AppError::Database(e),
+        }
+    }
+}
+
+impl From<PoolError> for AppError {
+    fn from(e: PoolError) -> Self {
+        AppError::Pool(e)
+    }
+}
+
+impl From<io::Error> for AppError {
+    fn from(e: io::Error) -> Self {
+        AppError::Internal(e.to_string())
+    }
+}
+
+impl From<bcrypt::BcryptError> for AppError {
+    fn from(e: bcrypt::BcryptError) -> Self {
+        AppError::Password(e)
+    }
+}
+
+impl<E: Into<AppError> + fmt::Debug> From<BlockingError<E>> for AppError {
+    fn from(e: BlockingError<E>) -> Self {
+        match e {
+            BlockingError::Error(e) => e.into(),
+            BlockingError::Canceled => AppError::Canceled,
+        }
+    }
+}
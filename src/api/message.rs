@@ -1,286 +1,1448 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Instant,
+};
 
 use actix::{
     fut, Actor, ActorContext, ActorFuture, Addr, AsyncContext, Context, ContextFutureSpawner,
-    Handler, Recipient, Running, StreamHandler, WrapFuture,
+    Handler, MessageResult, Recipient, Running, SpawnHandle, StreamHandler, WrapFuture,
 };
-use actix_identity::Identity;
-use actix_web::{error::BlockingError, web, Error, HttpRequest, HttpResponse, Responder};
+use actix_web::{web, Error, HttpRequest, HttpResponse, Responder, ResponseError};
 use actix_web_actors::ws;
-use chrono::{NaiveDateTime, Utc};
-use diesel::prelude::*;
-use message::{Disconnect, SendMessageModel, StreamMessage};
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::{pg::Pg, prelude::*};
+use message::{
+    Disconnect, EditMessageModel, EditedEvent, GetPresence, MessageContent, MessageRevisionInfo,
+    PresenceEvent, QuotedMessage, ReactionEvent, ReactionInfo, ReadConversationModel, ReadEvent,
+    RecalledEvent, RefreshPresence, RevokeSessions, SendMessageModel, SetAway, StartTyping,
+    StopTyping, StreamAck, StreamAction, StreamError, StreamEvent, StreamMessage, StreamModel,
+    StreamRequest, SyncModel, SyncResult, SyncedEvent, TypingEvent, TypingModel, TypingTarget,
+};
+use serde::Serialize;
 
 use crate::{
+    api::{
+        attachment::{load_attachments, to_attachment_info},
+        auth::AuthUser,
+        error::AppError,
+        user::{blocked_between, friend_ids, to_user_info},
+    },
+    config::PagingConfig,
     model::{
-        message::{self, Connect, HistoryPageModel},
-        ResultModel,
+        attachment::AttachmentInfo,
+        message::{
+            self, ChatList, ChatListModel, ChatSummary, Connect, ConversationInfo,
+            CreateConversationModel, MessageSearchModel, MessageSearchResult,
+        },
+        user::PresenceStatus,
+        CursorModel, PageModel, ResultModel,
     },
-    schema, DbPool,
+    schema::{self, NewConversationMember},
+    storage::Storage,
+    DbPool,
 };
 
+const MAX_EMOJI_LENGTH: usize = 32;
+const QUOTE_PREVIEW_LENGTH: usize = 100;
+/// How long a typing indicator lasts unless the client sends `typingStart` again.
+const TYPING_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(6);
+/// Repeated `typingStart` frames are relayed at most once per this interval.
+const TYPING_THROTTLE: std::time::Duration = std::time::Duration::from_secs(3);
+/// How long a session reuses the recipients it looked up for a typing target.
+const TYPING_RECIPIENTS_TTL: std::time::Duration = std::time::Duration::from_secs(30);
+/// `MessageContent` kinds as stored in the `message_type` column of `messages`.
+const MESSAGE_TYPE_TEXT: i32 = 0;
+const MESSAGE_TYPE_IMAGE: i32 = 1;
+const MESSAGE_TYPE_FILE: i32 = 2;
+const MESSAGE_TYPE_LOCATION: i32 = 3;
+const MESSAGE_TYPE_STICKER: i32 = 4;
+const MESSAGE_TYPE_SYSTEM: i32 = 5;
+const MESSAGE_TYPE_CONTACT_CARD: i32 = 6;
+
+/// Tunables of the messaging endpoints, shared as app data.
+#[derive(Clone, Debug)]
+pub struct MessageSettings {
+    /// How long after sending a message its sender may still recall it.
+    pub recall_window: Duration,
+}
+
 pub fn config(cfg: &mut web::ServiceConfig) {
     cfg.route("/list", web::get().to(list));
     cfg.route("/history/{user_id}", web::get().to(history));
     cfg.route("/send", web::post().to(send));
     cfg.route("/stream", web::get().to(stream));
+    cfg.route("/read", web::post().to(set_read_all));
     cfg.route("/read/{msg_id}", web::post().to(set_read));
+    cfg.route("/edit/{msg_id}", web::post().to(edit));
+    cfg.route("/revisions/{msg_id}", web::get().to(revisions));
+    cfg.route("/delete/{msg_id}", web::post().to(delete_for_me));
+    cfg.route("/recall/{msg_id}", web::post().to(recall));
+    cfg.route("/replies/{msg_id}", web::get().to(replies));
+    cfg.route("/reactions/{msg_id}/{emoji}", web::post().to(add_reaction));
+    cfg.route(
+        "/reactions/{msg_id}/{emoji}",
+        web::delete().to(remove_reaction),
+    );
+    cfg.route("/search", web::get().to(search));
+    cfg.route("/sync", web::get().to(sync));
+    cfg.route("/conversations", web::get().to(conversations));
+    cfg.route("/conversations", web::post().to(create_conversation));
+    cfg.route(
+        "/conversations/{conversation_id}",
+        web::get().to(conversation),
+    );
+    cfg.route(
+        "/conversations/{conversation_id}/history",
+        web::get().to(conversation_history),
+    );
+    cfg.route(
+        "/conversations/{conversation_id}/members/{user_id}",
+        web::post().to(invite_member),
+    );
+    cfg.route(
+        "/conversations/{conversation_id}/members/{user_id}",
+        web::delete().to(remove_member),
+    );
+    cfg.route(
+        "/conversations/{conversation_id}/leave",
+        web::post().to(leave_conversation),
+    );
 }
 
-pub async fn list(identity: Identity, pool: web::Data<DbPool>) -> impl Responder {
-    let conn = pool.get().expect("Failed to get connection from pool.");
-    match identity.identity() {
-        Some(user_id_str) => {
-            let self_user_id = user_id_str.parse::<i32>().unwrap();
-            match web::block(move || {
-                schema::messages::dsl::messages
-                    .filter(
-                        schema::messages::dsl::from_user
-                            .eq(&self_user_id)
-                            .or(schema::messages::dsl::to_user.eq(&self_user_id)),
-                    )
-                    .select((
-                        schema::messages::dsl::id,
-                        schema::messages::dsl::quote_id,
-                        schema::messages::dsl::read_time,
-                        schema::messages::dsl::message_type,
-                        schema::messages::dsl::message,
-                        schema::messages::dsl::send_time,
-                        schema::messages::dsl::from_user,
-                        schema::messages::dsl::to_user,
-                    ))
-                    .order(schema::messages::dsl::send_time.desc())
-                    .load::<(
-                        i32,
-                        Option<i32>,
-                        Option<NaiveDateTime>,
-                        i32,
-                        String,
-                        NaiveDateTime,
-                        i32,
-                        i32,
-                    )>(&conn)
-            })
-            .await
-            {
-                Ok(result) => {
-                    let mut message_map = HashMap::<i32, message::Message>::new();
-                    for item in &result {
-                        let f = item.clone();
-                        let display_user_id = if f.6 == self_user_id { f.7 } else { f.6 };
-                        if !message_map.contains_key(&display_user_id) {
-                            message_map.insert(
-                                display_user_id,
-                                message::Message {
-                                    id: f.0,
-                                    quote_id: f.1,
-                                    read_time: f.2,
-                                    message_type: f.3,
-                                    message: f.4,
-                                    send_time: f.5,
-                                    from_user: f.6,
-                                    to_user: f.7,
-                                },
-                            );
-                        };
-                    }
+/// Splits message content into the `message_type` and `message` columns. Text is
+/// stored as is, other payloads as JSON.
+fn encode_content(content: &MessageContent) -> (i32, String) {
+    match content {
+        MessageContent::Text(text) => (MESSAGE_TYPE_TEXT, text.clone()),
+        MessageContent::Image(payload) => (MESSAGE_TYPE_IMAGE, encode_payload(payload)),
+        MessageContent::File(payload) => (MESSAGE_TYPE_FILE, encode_payload(payload)),
+        MessageContent::Location(payload) => (MESSAGE_TYPE_LOCATION, encode_payload(payload)),
+        MessageContent::Sticker(payload) => (MESSAGE_TYPE_STICKER, encode_payload(payload)),
+        MessageContent::System(text) => (MESSAGE_TYPE_SYSTEM, text.clone()),
+        MessageContent::ContactCard(payload) => {
+            (MESSAGE_TYPE_CONTACT_CARD, encode_payload(payload))
+        }
+    }
+}
 
-                    let mut message_result = Vec::<message::Message>::new();
-                    for item in message_map {
-                        message_result.push(item.1);
-                    }
+fn encode_payload<T: Serialize>(payload: &T) -> String {
+    // The payloads are plain structs of strings and numbers, which always serialize.
+    serde_json::to_string(payload).expect("Message payloads serialize to JSON.")
+}
 
-                    ResultModel {
-                        success: true,
-                        code: 200,
-                        data: Some(message_result),
-                        message: None,
-                    }
-                }
-                Err(BlockingError::Error(e)) => ResultModel {
-                    success: false,
-                    code: 500,
-                    data: None,
-                    message: Some(e.to_string()),
-                },
-                Err(BlockingError::Canceled) => ResultModel {
-                    success: false,
-                    code: 500,
-                    data: None,
-                    message: Some("Operation has been cancelled.".to_string()),
-                },
+/// Reverses `encode_content`. Rows that don't parse, such as recalled messages, come
+/// back as text.
+fn decode_content(content_type: i32, payload: &str) -> MessageContent {
+    let decoded = match content_type {
+        MESSAGE_TYPE_IMAGE => serde_json::from_str(payload).map(MessageContent::Image),
+        MESSAGE_TYPE_FILE => serde_json::from_str(payload).map(MessageContent::File),
+        MESSAGE_TYPE_LOCATION => serde_json::from_str(payload).map(MessageContent::Location),
+        MESSAGE_TYPE_STICKER => serde_json::from_str(payload).map(MessageContent::Sticker),
+        MESSAGE_TYPE_SYSTEM => Ok(MessageContent::System(payload.to_string())),
+        MESSAGE_TYPE_CONTACT_CARD => serde_json::from_str(payload).map(MessageContent::ContactCard),
+        _ => Ok(MessageContent::Text(payload.to_string())),
+    };
+    decoded.unwrap_or_else(|_| MessageContent::Text(payload.to_string()))
+}
+
+/// Checks the payload of a message about to be sent. Attachment types of image
+/// messages are checked once the attachments are claimed.
+fn validate_content(conn: &PgConnection, model: &SendMessageModel) -> Result<(), AppError> {
+    let error = |message: &str| Err(AppError::BadRequest(message.to_string()));
+    match &model.content {
+        MessageContent::Text(text) if text.trim().is_empty() => error("Message cannot be empty."),
+        MessageContent::Image(_) | MessageContent::File(_) if model.attachments.is_empty() => {
+            error("Attachments are required for this message type.")
+        }
+        MessageContent::Location(location)
+            if !(-90.0..=90.0).contains(&location.latitude)
+                || !(-180.0..=180.0).contains(&location.longitude) =>
+        {
+            error("Invalid location.")
+        }
+        MessageContent::Sticker(sticker)
+            if sticker.pack.is_empty() || sticker.sticker.is_empty() =>
+        {
+            error("Invalid sticker.")
+        }
+        MessageContent::System(_) => error("System messages cannot be sent."),
+        MessageContent::ContactCard(card) => match schema::users::dsl::users
+            .filter(schema::users::dsl::id.eq(&card.user_id))
+            .select(schema::users::dsl::id)
+            .first::<i32>(conn)
+            .optional()?
+        {
+            Some(_) => Ok(()),
+            None => error("Invalid contact card."),
+        },
+        _ => Ok(()),
+    }
+}
+
+fn to_message_model(
+    f: schema::Message,
+    attachments: Vec<AttachmentInfo>,
+    reactions: Vec<ReactionInfo>,
+    quote: Option<QuotedMessage>,
+) -> message::Message {
+    message::Message {
+        id: f.id,
+        quote_id: f.quote_id,
+        read_time: f.read_time,
+        edited_time: f.edited_time,
+        recalled_time: f.recalled_time,
+        content: decode_content(f.message_type, &f.message),
+        send_time: f.send_time,
+        from_user: f.from_user,
+        to_user: f.to_user,
+        conversation_id: f.conversation_id,
+        attachments,
+        reactions,
+        quote,
+    }
+}
+
+fn to_quoted_message(f: &schema::Message) -> QuotedMessage {
+    QuotedMessage {
+        id: f.id,
+        from_user: f.from_user,
+        content: match decode_content(f.message_type, &f.message) {
+            MessageContent::Text(text) => {
+                MessageContent::Text(text.chars().take(QUOTE_PREVIEW_LENGTH).collect())
+            }
+            content => content,
+        },
+        recalled: f.recalled_time.is_some(),
+    }
+}
+
+/// Aggregates the reactions on the given messages per emoji, in the order each emoji
+/// was first used.
+fn load_reactions(
+    conn: &PgConnection,
+    self_user_id: i32,
+    message_ids: &[i32],
+) -> QueryResult<HashMap<i32, Vec<ReactionInfo>>> {
+    let mut result = HashMap::<i32, Vec<ReactionInfo>>::new();
+    if message_ids.is_empty() {
+        return Ok(result);
+    }
+    for item in schema::message_reactions::dsl::message_reactions
+        .filter(schema::message_reactions::dsl::message_id.eq_any(message_ids))
+        .order(schema::message_reactions::dsl::id.asc())
+        .load::<schema::MessageReaction>(conn)?
+    {
+        let reactions = result.entry(item.message_id).or_default();
+        let position = match reactions
+            .iter()
+            .position(|reaction| reaction.emoji == item.emoji)
+        {
+            Some(position) => position,
+            None => {
+                reactions.push(ReactionInfo {
+                    emoji: item.emoji,
+                    count: 0,
+                    reacted: false,
+                });
+                reactions.len() - 1
             }
+        };
+        reactions[position].count += 1;
+        reactions[position].reacted |= item.user_id == self_user_id;
+    }
+    Ok(result)
+}
+
+fn to_message_models(
+    conn: &PgConnection,
+    self_user_id: i32,
+    result: Vec<schema::Message>,
+) -> QueryResult<Vec<message::Message>> {
+    let message_ids = result.iter().map(|item| item.id).collect::<Vec<_>>();
+    let mut attachments = load_attachments(conn, &message_ids)?;
+    let mut reactions = load_reactions(conn, self_user_id, &message_ids)?;
+    let quotes = schema::messages::dsl::messages
+        .filter(
+            schema::messages::dsl::id.eq_any(
+                result
+                    .iter()
+                    .filter_map(|item| item.quote_id)
+                    .collect::<Vec<_>>(),
+            ),
+        )
+        .load::<schema::Message>(conn)?
+        .into_iter()
+        .map(|item| (item.id, item))
+        .collect::<HashMap<_, _>>();
+    Ok(result
+        .into_iter()
+        .map(|item| {
+            let item_attachments = attachments.remove(&item.id).unwrap_or_default();
+            let item_reactions = reactions.remove(&item.id).unwrap_or_default();
+            let item_quote = item
+                .quote_id
+                .and_then(|item_quote_id| quotes.get(&item_quote_id))
+                .map(to_quoted_message);
+            to_message_model(item, item_attachments, item_reactions, item_quote)
+        })
+        .collect::<Vec<_>>())
+}
+
+fn to_stream_message(f: &schema::Message, attachments: Vec<AttachmentInfo>) -> StreamMessage {
+    StreamMessage {
+        id: f.id,
+        user_id: f.from_user,
+        conversation_id: f.conversation_id,
+        quote_id: f.quote_id,
+        send_time: f.send_time,
+        edited_time: f.edited_time,
+        recalled_time: f.recalled_time,
+        content: decode_content(f.message_type, &f.message),
+        attachments,
+    }
+}
+
+fn joined_conversation_ids(conn: &PgConnection, self_user_id: i32) -> QueryResult<Vec<i32>> {
+    schema::conversation_members::dsl::conversation_members
+        .filter(schema::conversation_members::dsl::user_id.eq(&self_user_id))
+        .select(schema::conversation_members::dsl::conversation_id)
+        .load::<i32>(conn)
+}
+
+/// Loads messages visible to `self_user_id` newer than the given id and/or time,
+/// oldest first.
+fn messages_since(
+    conn: &PgConnection,
+    self_user_id: i32,
+    since_id: Option<i32>,
+    since_time: Option<NaiveDateTime>,
+    limit: Option<i64>,
+) -> QueryResult<Vec<schema::Message>> {
+    let conversation_ids = joined_conversation_ids(conn, self_user_id)?;
+    let mut query = schema::messages::dsl::messages
+        .filter(
+            schema::messages::dsl::from_user
+                .eq(self_user_id)
+                .and(schema::messages::dsl::to_user.is_not_null())
+                .or(schema::messages::dsl::to_user.eq(self_user_id))
+                .or(schema::messages::dsl::conversation_id.eq_any(conversation_ids)),
+        )
+        .filter(
+            schema::messages::dsl::id.ne_all(
+                schema::message_deletions::dsl::message_deletions
+                    .filter(schema::message_deletions::dsl::user_id.eq(self_user_id))
+                    .select(schema::message_deletions::dsl::message_id),
+            ),
+        )
+        .order(schema::messages::dsl::id.asc())
+        .into_boxed();
+    if let Some(since_id) = since_id {
+        query = query.filter(schema::messages::dsl::id.gt(since_id));
+    }
+    if let Some(since_time) = since_time {
+        query = query.filter(schema::messages::dsl::send_time.gt(since_time));
+    }
+    if let Some(limit) = limit {
+        query = query.limit(limit);
+    }
+    query.load::<schema::Message>(conn)
+}
+
+/// Loads one page of messages matched by `query` following `cursor`: `before` pages
+/// towards older messages, `after` towards newer ones. Without a cursor paging starts
+/// from the newest message, or from the oldest when `oldest_first` is set.
+fn message_page<'a>(
+    conn: &PgConnection,
+    self_user_id: i32,
+    query: schema::messages::BoxedQuery<'a, Pg>,
+    cursor: &CursorModel,
+    paging: &PagingConfig,
+    oldest_first: bool,
+) -> QueryResult<PageModel<message::Message>> {
+    let limit = paging.page_size(cursor.limit);
+    let mut query = query.filter(
+        schema::messages::dsl::id.ne_all(
+            schema::message_deletions::dsl::message_deletions
+                .filter(schema::message_deletions::dsl::user_id.eq(self_user_id))
+                .select(schema::message_deletions::dsl::message_id),
+        ),
+    );
+    let forward = match (cursor.before, cursor.after) {
+        (Some(before), _) => {
+            query = query.filter(schema::messages::dsl::id.lt(before));
+            false
+        }
+        (None, Some(after)) => {
+            query = query.filter(schema::messages::dsl::id.gt(after));
+            true
         }
-        None => ResultModel {
-            success: false,
-            code: 401,
-            data: None,
-            message: Some("Not logged in.".to_string()),
+        (None, None) => oldest_first,
+    };
+    query = if forward {
+        query.order(schema::messages::dsl::id.asc())
+    } else {
+        query.order(schema::messages::dsl::id.desc())
+    };
+    let mut result = query.limit(limit + 1).load::<schema::Message>(conn)?;
+    let has_more = result.len() as i64 > limit;
+    result.truncate(limit as usize);
+    Ok(PageModel {
+        next_cursor: if has_more {
+            result.last().map(|item| item.id)
+        } else {
+            None
         },
+        has_more,
+        items: to_message_models(conn, self_user_id, result)?,
+    })
+}
+
+fn member_ids(conn: &PgConnection, conversation_id: i32) -> QueryResult<Vec<i32>> {
+    schema::conversation_members::dsl::conversation_members
+        .filter(schema::conversation_members::dsl::conversation_id.eq(&conversation_id))
+        .order(schema::conversation_members::dsl::join_time.asc())
+        .select(schema::conversation_members::dsl::user_id)
+        .load::<i32>(conn)
+}
+
+/// The newest message of a conversation, which new members start out having read.
+fn latest_message_id(conn: &PgConnection, conversation_id: i32) -> QueryResult<Option<i32>> {
+    schema::messages::dsl::messages
+        .filter(schema::messages::dsl::conversation_id.eq(&conversation_id))
+        .select(diesel::dsl::max(schema::messages::dsl::id))
+        .first::<Option<i32>>(conn)
+}
+
+fn conversation_info(conn: &PgConnection, conversation_id: i32) -> QueryResult<ConversationInfo> {
+    let conversation = schema::conversations::dsl::conversations
+        .filter(schema::conversations::dsl::id.eq(&conversation_id))
+        .first::<schema::Conversation>(conn)?;
+    Ok(ConversationInfo {
+        id: conversation.id,
+        name: conversation.name,
+        owner_id: conversation.owner_id,
+        create_time: conversation.create_time,
+        members: member_ids(conn, conversation_id)?,
+    })
+}
+
+/// Loads a conversation `self_user_id` is a member of. Other conversations are
+/// reported as missing rather than forbidden.
+fn joined_conversation(
+    conn: &PgConnection,
+    self_user_id: i32,
+    conversation_id: i32,
+) -> Result<ConversationInfo, AppError> {
+    conversation_info(conn, conversation_id)
+        .optional()?
+        .filter(|info| info.members.contains(&self_user_id))
+        .ok_or_else(|| AppError::NotFound("Conversation doesn't exists.".to_string()))
+}
+
+/// Latest message of every chat visible to `$1`, keyed by peer for direct chats and by
+/// conversation for groups, most recently active first.
+const LATEST_MESSAGES_SQL: &str = r#"
+SELECT * FROM (
+    SELECT DISTINCT ON ("chat_peer", "conversation_id") "messages".*,
+        CASE WHEN "conversation_id" IS NULL THEN
+            CASE WHEN "from_user" = $1 THEN "to_user" ELSE "from_user" END
+        END AS "chat_peer"
+    FROM "messages"
+    WHERE ("to_user" IS NOT NULL AND ("from_user" = $1 OR "to_user" = $1)
+        OR "conversation_id" IN (
+            SELECT "conversation_id" FROM "conversation_members" WHERE "user_id" = $1))
+        AND "id" NOT IN (SELECT "message_id" FROM "message_deletions" WHERE "user_id" = $1)
+    ORDER BY "chat_peer", "conversation_id", "id" DESC
+) AS "latest"
+WHERE "id" < $2
+ORDER BY "id" DESC
+LIMIT $3
+"#;
+
+/// Unread messages of `$1` per chat: direct messages without `read_time`, and group
+/// messages past the member's `last_read_id`.
+const UNREAD_COUNTS_SQL: &str = r#"
+SELECT "from_user" AS "peer_id", NULL::integer AS "conversation_id", COUNT(*) AS "unread"
+FROM "messages"
+WHERE "to_user" = $1 AND "read_time" IS NULL
+    AND "id" NOT IN (SELECT "message_id" FROM "message_deletions" WHERE "user_id" = $1)
+GROUP BY "from_user"
+UNION ALL
+SELECT NULL::integer, "messages"."conversation_id", COUNT(*)
+FROM "messages"
+INNER JOIN "conversation_members"
+    ON "conversation_members"."conversation_id" = "messages"."conversation_id"
+    AND "conversation_members"."user_id" = $1
+WHERE "messages"."from_user" <> $1
+    AND "messages"."id" > COALESCE("conversation_members"."last_read_id", 0)
+    AND "messages"."id" NOT IN (SELECT "message_id" FROM "message_deletions" WHERE "user_id" = $1)
+GROUP BY "messages"."conversation_id"
+"#;
+
+/// Text messages visible to `$1` matching the web search query `$2`, newest first,
+/// optionally narrowed to the direct chat with `$3`, the conversation `$4` and the
+/// `[$5, $6)` time range. Matches in the snippet are delimited by `MARK_START` and
+/// `MARK_END`, which are stripped from the text beforehand.
+const SEARCH_MESSAGES_SQL: &str = r#"
+SELECT "messages".*,
+    ts_headline('simple',
+        translate("message", chr(1) || chr(2), ''),
+        "search_query",
+        'StartSel=' || chr(1) || ', StopSel=' || chr(2)
+            || ', MaxWords=20, MinWords=5, MaxFragments=2'
+    ) AS "snippet"
+FROM "messages", websearch_to_tsquery('simple', $2) AS "search_query"
+WHERE "search_vector" @@ "search_query"
+    AND ("to_user" IS NOT NULL AND ("from_user" = $1 OR "to_user" = $1)
+        OR "conversation_id" IN (
+            SELECT "conversation_id" FROM "conversation_members" WHERE "user_id" = $1))
+    AND "id" NOT IN (SELECT "message_id" FROM "message_deletions" WHERE "user_id" = $1)
+    AND ($3::integer IS NULL
+        OR "conversation_id" IS NULL AND ("from_user" = $3 OR "to_user" = $3))
+    AND ($4::integer IS NULL OR "conversation_id" = $4)
+    AND ($5::timestamp IS NULL OR "send_time" >= $5)
+    AND ($6::timestamp IS NULL OR "send_time" < $6)
+    AND "id" < $7
+ORDER BY "id" DESC
+LIMIT $8
+"#;
+
+/// Control characters that `SEARCH_MESSAGES_SQL` marks matches with, so that the
+/// highlighting is done on the raw text rather than inside HTML entities.
+const MARK_START: char = '\u{1}';
+const MARK_END: char = '\u{2}';
+
+/// Escapes a search snippet for HTML and turns its match markers into `<mark>` tags.
+fn highlight_snippet(snippet: &str) -> String {
+    let mut result = String::with_capacity(snippet.len());
+    for c in snippet.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            MARK_START => result.push_str("<mark>"),
+            MARK_END => result.push_str("</mark>"),
+            _ => result.push(c),
+        }
     }
+    result
+}
+
+fn search_messages(
+    conn: &PgConnection,
+    self_user_id: i32,
+    query: &MessageSearchModel,
+    paging: &PagingConfig,
+) -> QueryResult<PageModel<MessageSearchResult>> {
+    let limit = paging.page_size(query.limit);
+    let mut hits = diesel::sql_query(SEARCH_MESSAGES_SQL)
+        .bind::<diesel::sql_types::Integer, _>(self_user_id)
+        .bind::<diesel::sql_types::Text, _>(&query.patterns)
+        .bind::<diesel::sql_types::Nullable<diesel::sql_types::Integer>, _>(query.user_id)
+        .bind::<diesel::sql_types::Nullable<diesel::sql_types::Integer>, _>(query.conversation_id)
+        .bind::<diesel::sql_types::Nullable<diesel::sql_types::Timestamp>, _>(query.since)
+        .bind::<diesel::sql_types::Nullable<diesel::sql_types::Timestamp>, _>(query.until)
+        .bind::<diesel::sql_types::Integer, _>(query.before.unwrap_or(i32::MAX))
+        .bind::<diesel::sql_types::BigInt, _>(limit + 1)
+        .load::<schema::MessageSearchHit>(conn)?;
+    let has_more = hits.len() as i64 > limit;
+    hits.truncate(limit as usize);
+    let next_cursor = if has_more {
+        hits.last().map(|item| item.message.id)
+    } else {
+        None
+    };
+    let (messages, snippets): (Vec<_>, Vec<_>) = hits
+        .into_iter()
+        .map(|item| (item.message, highlight_snippet(&item.snippet)))
+        .unzip();
+    Ok(PageModel {
+        items: to_message_models(conn, self_user_id, messages)?
+            .into_iter()
+            .zip(snippets)
+            .map(|(message, snippet)| MessageSearchResult { message, snippet })
+            .collect::<Vec<_>>(),
+        has_more,
+        next_cursor,
+    })
+}
+
+fn chat_list(
+    conn: &PgConnection,
+    self_user_id: i32,
+    query: &ChatListModel,
+    paging: &PagingConfig,
+) -> QueryResult<ChatList> {
+    let limit = paging.page_size(query.limit);
+    let mut latest = diesel::sql_query(LATEST_MESSAGES_SQL)
+        .bind::<diesel::sql_types::Integer, _>(self_user_id)
+        .bind::<diesel::sql_types::Integer, _>(query.before.unwrap_or(i32::MAX))
+        .bind::<diesel::sql_types::BigInt, _>(limit + 1)
+        .load::<schema::Message>(conn)?;
+    let has_more = latest.len() as i64 > limit;
+    latest.truncate(limit as usize);
+    let next_cursor = if has_more {
+        latest.last().map(|item| item.id)
+    } else {
+        None
+    };
+
+    let unread_counts = diesel::sql_query(UNREAD_COUNTS_SQL)
+        .bind::<diesel::sql_types::Integer, _>(self_user_id)
+        .load::<schema::UnreadCount>(conn)?;
+    let total_unread = unread_counts.iter().map(|item| item.unread).sum::<i64>();
+    let unread_counts = unread_counts
+        .into_iter()
+        .map(|item| ((item.peer_id, item.conversation_id), item.unread))
+        .collect::<HashMap<_, _>>();
+
+    let peer_id = |item: &schema::Message| {
+        item.to_user.map(|to_user| {
+            if item.from_user == self_user_id {
+                to_user
+            } else {
+                item.from_user
+            }
+        })
+    };
+    let chats = latest
+        .iter()
+        .map(|item| (peer_id(item), item.conversation_id))
+        .collect::<Vec<_>>();
+    let friend_ids = friend_ids(conn, self_user_id)?;
+    let mut users = schema::users::dsl::users
+        .filter(
+            schema::users::dsl::id.eq_any(
+                chats
+                    .iter()
+                    .filter_map(|(item_peer_id, _)| *item_peer_id)
+                    .collect::<Vec<_>>(),
+            ),
+        )
+        .load::<schema::User>(conn)?
+        .into_iter()
+        .map(|item| {
+            let is_friend = friend_ids.contains(&item.id);
+            (item.id, to_user_info(item, self_user_id, is_friend))
+        })
+        .collect::<HashMap<_, _>>();
+    let conversation_names = schema::conversations::dsl::conversations
+        .filter(
+            schema::conversations::dsl::id.eq_any(
+                chats
+                    .iter()
+                    .filter_map(|(_, item_conversation_id)| *item_conversation_id)
+                    .collect::<Vec<_>>(),
+            ),
+        )
+        .select((
+            schema::conversations::dsl::id,
+            schema::conversations::dsl::name,
+        ))
+        .load::<(i32, String)>(conn)?
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+
+    Ok(ChatList {
+        items: chats
+            .into_iter()
+            .zip(to_message_models(conn, self_user_id, latest)?)
+            .map(
+                |((item_peer_id, item_conversation_id), last_message)| ChatSummary {
+                    user: item_peer_id.and_then(|item_peer_id| users.remove(&item_peer_id)),
+                    conversation_id: item_conversation_id,
+                    conversation_name: item_conversation_id
+                        .and_then(|item_conversation_id| {
+                            conversation_names.get(&item_conversation_id)
+                        })
+                        .cloned(),
+                    last_message,
+                    unread_count: unread_counts
+                        .get(&(item_peer_id, item_conversation_id))
+                        .copied()
+                        .unwrap_or(0),
+                },
+            )
+            .collect::<Vec<_>>(),
+        total_unread,
+        has_more,
+        next_cursor,
+    })
+}
+
+pub async fn list(
+    web::Query(query): web::Query<ChatListModel>,
+    auth: AuthUser,
+    pool: web::Data<DbPool>,
+    paging: web::Data<PagingConfig>,
+) -> Result<impl Responder, AppError> {
+    let conn = pool.get()?;
+    let self_user_id = auth.user_id;
+    let result = web::block(move || chat_list(&conn, self_user_id, &query, &paging)).await?;
+    Ok(ResultModel::ok(result))
+}
+
+pub async fn search(
+    web::Query(query): web::Query<MessageSearchModel>,
+    auth: AuthUser,
+    pool: web::Data<DbPool>,
+    paging: web::Data<PagingConfig>,
+) -> Result<impl Responder, AppError> {
+    let conn = pool.get()?;
+    let self_user_id = auth.user_id;
+    let result = web::block(move || search_messages(&conn, self_user_id, &query, &paging)).await?;
+    Ok(ResultModel::ok(result))
 }
 
 pub async fn history(
     web::Path(user_id): web::Path<i32>,
-    web::Query(query): web::Query<HistoryPageModel>,
-    identity: Identity,
+    web::Query(query): web::Query<CursorModel>,
+    auth: AuthUser,
     pool: web::Data<DbPool>,
-) -> impl Responder {
-    let conn = pool.get().expect("Failed to get connection from pool.");
-    match identity.identity() {
-        Some(user_id_str) => {
-            let self_user_id = user_id_str.parse::<i32>().unwrap();
-            match web::block(move || {
-                schema::messages::dsl::messages
-                    .filter(
-                        schema::messages::dsl::from_user
-                            .eq(&self_user_id)
-                            .and(schema::messages::dsl::to_user.eq(&user_id))
-                            .or(schema::messages::dsl::from_user
-                                .eq(&user_id)
-                                .and(schema::messages::dsl::to_user.eq(&self_user_id))),
-                    )
-                    .order(schema::messages::dsl::send_time.desc())
-                    .offset(match query.page {
-                        None => 0,
-                        Some(page) => ((page - 1) * 10).into(),
-                    })
-                    .limit(10)
-                    .load::<schema::Message>(&conn)
-            })
-            .await
-            {
-                Ok(result) => ResultModel {
-                    success: true,
-                    code: 200,
-                    data: Some(
-                        result
-                            .iter()
-                            .map(|item| {
-                                let f = item.clone();
-                                message::Message {
-                                    id: f.id,
-                                    quote_id: f.quote_id,
-                                    read_time: f.read_time,
-                                    message_type: f.message_type,
-                                    message: f.message,
-                                    send_time: f.send_time,
-                                    from_user: f.from_user,
-                                    to_user: f.to_user,
-                                }
-                            })
-                            .collect::<Vec<_>>(),
-                    ),
-                    message: None,
-                },
-                Err(BlockingError::Error(e)) => ResultModel {
-                    success: false,
-                    code: 500,
-                    data: None,
-                    message: Some(e.to_string()),
-                },
-                Err(BlockingError::Canceled) => ResultModel {
-                    success: false,
-                    code: 500,
-                    data: None,
-                    message: Some("Operation has been cancelled.".to_string()),
-                },
+    paging: web::Data<PagingConfig>,
+) -> Result<impl Responder, AppError> {
+    let conn = pool.get()?;
+    let self_user_id = auth.user_id;
+    let result = web::block(move || {
+        message_page(
+            &conn,
+            self_user_id,
+            schema::messages::dsl::messages
+                .filter(
+                    schema::messages::dsl::from_user
+                        .eq(&self_user_id)
+                        .and(schema::messages::dsl::to_user.eq(&user_id))
+                        .or(schema::messages::dsl::from_user
+                            .eq(&user_id)
+                            .and(schema::messages::dsl::to_user.eq(&self_user_id))),
+                )
+                .into_boxed(),
+            &query,
+            &paging,
+            false,
+        )
+    })
+    .await?;
+    Ok(ResultModel::ok(result))
+}
+
+/// Whether a new message from `self_user_id` may quote `quote`, i.e. both belong to
+/// the same conversation.
+fn in_same_conversation(
+    quote: &schema::Message,
+    self_user_id: i32,
+    model: &SendMessageModel,
+) -> bool {
+    match quote.conversation_id {
+        Some(_) => quote.conversation_id == model.conversation_id,
+        None => {
+            quote.from_user == self_user_id && quote.to_user == model.to_user
+                || Some(quote.from_user) == model.to_user && quote.to_user == Some(self_user_id)
+        }
+    }
+}
+
+/// Validates and persists a message sent by `self_user_id`, then pushes it to the
+/// recipients' streams. Shared by the HTTP `/send` endpoint and the `/stream` protocol.
+fn deliver_message(
+    conn: &PgConnection,
+    stream: &Addr<MessageStreamServer>,
+    self_user_id: i32,
+    model: &SendMessageModel,
+) -> Result<message::Message, AppError> {
+    if model.to_user.is_some() == model.conversation_id.is_some() {
+        return Err(AppError::BadRequest(
+            "Exactly one of toUser and conversationId is required.".to_string(),
+        ));
+    }
+    if model.to_user == Some(self_user_id) {
+        return Err(AppError::BadRequest(
+            "Cannot send message to yourself.".to_string(),
+        ));
+    }
+    if let Some(target_user_id) = model.to_user {
+        if blocked_between(conn, self_user_id, target_user_id)? {
+            return Err(AppError::Forbidden(
+                "Cannot send message to this user.".to_string(),
+            ));
+        }
+    }
+    let recipients = match model.conversation_id {
+        Some(target_conversation_id) => {
+            let members = member_ids(conn, target_conversation_id)?;
+            if !members.contains(&self_user_id) {
+                return Err(AppError::Forbidden(
+                    "Not a member of this conversation.".to_string(),
+                ));
             }
+            members
+                .into_iter()
+                .filter(|member| *member != self_user_id)
+                .collect::<Vec<_>>()
         }
-        None => ResultModel {
-            success: false,
-            code: 401,
-            data: None,
-            message: Some("Not logged in.".to_string()),
+        None => model.to_user.into_iter().collect::<Vec<_>>(),
+    };
+    let quote = match model.quote_id {
+        Some(target_quote_id) => match schema::messages::dsl::messages
+            .filter(schema::messages::dsl::id.eq(&target_quote_id))
+            .first::<schema::Message>(conn)
+            .optional()?
+        {
+            Some(quote) if in_same_conversation(&quote, self_user_id, model) => Some(quote),
+            _ => return Err(AppError::BadRequest("Invalid quoted message.".to_string())),
         },
+        None => None,
+    };
+    validate_content(conn, model)?;
+    let (content_type, payload) = encode_content(&model.content);
+    let mut attachment_ids = model.attachments.clone();
+    attachment_ids.sort_unstable();
+    attachment_ids.dedup();
+    use schema::messages::dsl::*;
+    let (sent_msg, attached) = conn
+        .transaction::<_, diesel::result::Error, _>(|| {
+            let sent_msg = diesel::insert_into(messages)
+                .values((
+                    quote_id.eq(&model.quote_id),
+                    from_user.eq(&self_user_id),
+                    to_user.eq(&model.to_user),
+                    conversation_id.eq(&model.conversation_id),
+                    message_type.eq(&content_type),
+                    message.eq(&payload),
+                    send_time.eq(&Utc::now().naive_utc()),
+                ))
+                .get_result::<schema::Message>(conn)?;
+            // Only the uploader's own, not yet sent attachments can be attached.
+            let attached = diesel::update(
+                schema::attachments::dsl::attachments.filter(
+                    schema::attachments::dsl::id
+                        .eq_any(&attachment_ids)
+                        .and(schema::attachments::dsl::uploader_id.eq(&self_user_id))
+                        .and(schema::attachments::dsl::message_id.is_null()),
+                ),
+            )
+            .set(schema::attachments::dsl::message_id.eq(&sent_msg.id))
+            .get_results::<schema::Attachment>(conn)?;
+            if attached.len() != attachment_ids.len()
+                || matches!(model.content, MessageContent::Image(_))
+                    && attached
+                        .iter()
+                        .any(|item| !item.content_type.starts_with("image/"))
+            {
+                return Err(diesel::result::Error::RollbackTransaction);
+            }
+            Ok((
+                sent_msg,
+                attached.iter().map(to_attachment_info).collect::<Vec<_>>(),
+            ))
+        })
+        .map_err(|e| match e {
+            diesel::result::Error::RollbackTransaction => {
+                AppError::BadRequest("Invalid attachments.".to_string())
+            }
+            e => e.into(),
+        })?;
+    if let Some(target) = typing_target(model.to_user, model.conversation_id) {
+        stream.do_send(StopTyping {
+            user_id: self_user_id,
+            target,
+        });
     }
+    stream.do_send(TargetStreamMessage {
+        message: StreamEvent::Message(to_stream_message(&sent_msg, attached.clone())),
+        user_ids: recipients,
+    });
+    Ok(to_message_model(
+        sent_msg,
+        attached,
+        Vec::new(),
+        quote.as_ref().map(to_quoted_message),
+    ))
 }
 
-pub async fn send(
-    web::Json(model): web::Json<SendMessageModel>,
-    identity: Identity,
-    pool: web::Data<DbPool>,
-    stream: web::Data<Addr<MessageStreamServer>>,
-) -> impl Responder {
-    let conn = pool.get().expect("Failed to get connection from pool.");
-    match identity.identity() {
-        Some(user_id_str) => {
-            let self_user_id = user_id_str.parse::<i32>().unwrap();
-            if self_user_id == model.to_user {
-                return ResultModel::<String> {
-                    success: false,
-                    code: 400,
-                    data: None,
-                    message: Some("Cannot send message to yourself.".to_string()),
-                };
+fn message_not_found() -> AppError {
+    AppError::NotFound("Message doesn't exists.".to_string())
+}
+
+/// Sends read receipts for `read` (pairs of message id and sender) to each sender.
+fn notify_read(
+    stream: &Addr<MessageStreamServer>,
+    self_user_id: i32,
+    conversation_id: Option<i32>,
+    read_time: NaiveDateTime,
+    read: Vec<(i32, i32)>,
+) {
+    let mut receipts = HashMap::<i32, Vec<i32>>::new();
+    for (message_id, sender) in read {
+        receipts.entry(sender).or_default().push(message_id);
+    }
+    for (sender, message_ids) in receipts {
+        stream.do_send(TargetStreamMessage {
+            message: StreamEvent::Read(ReadEvent {
+                user_id: self_user_id,
+                conversation_id,
+                message_ids,
+                read_time,
+            }),
+            user_ids: vec![sender],
+        });
+    }
+}
+
+/// Marks every message in a chat up to `up_to_id` as read by `self_user_id`.
+/// Direct messages get their `read_time` set, while group conversations track
+/// the position in `conversation_members.last_read_id`.
+fn mark_conversation_read(
+    conn: &PgConnection,
+    stream: &Addr<MessageStreamServer>,
+    self_user_id: i32,
+    model: &ReadConversationModel,
+) -> Result<(), AppError> {
+    let now = Utc::now().naive_utc();
+    match (model.user_id, model.conversation_id) {
+        (Some(peer_user_id), None) => {
+            let read = diesel::update(
+                schema::messages::dsl::messages.filter(
+                    schema::messages::dsl::from_user
+                        .eq(&peer_user_id)
+                        .and(schema::messages::dsl::to_user.eq(&self_user_id))
+                        .and(schema::messages::dsl::id.le(&model.up_to_id))
+                        .and(schema::messages::dsl::read_time.is_null()),
+                ),
+            )
+            .set(schema::messages::dsl::read_time.eq(&now))
+            .returning((schema::messages::dsl::id, schema::messages::dsl::from_user))
+            .get_results::<(i32, i32)>(conn)?;
+            notify_read(stream, self_user_id, None, now, read);
+            Ok(())
+        }
+        (None, Some(target_conversation_id)) => {
+            let member = schema::conversation_members::dsl::conversation_members.filter(
+                schema::conversation_members::dsl::conversation_id
+                    .eq(&target_conversation_id)
+                    .and(schema::conversation_members::dsl::user_id.eq(&self_user_id)),
+            );
+            let last_read_id = member
+                .select(schema::conversation_members::dsl::last_read_id)
+                .first::<Option<i32>>(conn)
+                .optional()?
+                .ok_or_else(|| {
+                    AppError::Forbidden("Not a member of this conversation.".to_string())
+                })?
+                .unwrap_or(0);
+            // The read position only ever points at a real message, so a made-up id
+            // can't mark future messages as read.
+            let up_to_id = schema::messages::dsl::messages
+                .filter(
+                    schema::messages::dsl::conversation_id
+                        .eq(&target_conversation_id)
+                        .and(schema::messages::dsl::id.le(&model.up_to_id)),
+                )
+                .select(diesel::dsl::max(schema::messages::dsl::id))
+                .first::<Option<i32>>(conn)?
+                .ok_or_else(|| AppError::BadRequest("No message to mark as read.".to_string()))?;
+            if up_to_id <= last_read_id {
+                return Ok(());
             }
-            match web::block(move || {
-                use schema::messages::dsl::*;
-                let result = diesel::insert_into(messages)
-                    .values((
-                        quote_id.eq(&model.quote_id),
-                        from_user.eq(&self_user_id),
-                        to_user.eq(&model.to_user),
-                        message_type.eq(&model.message_type),
-                        message.eq(&model.message),
-                        send_time.eq(&Utc::now().naive_utc()),
-                    ))
-                    .get_result::<schema::Message>(&conn);
-                if let Ok(ref sent_msg) = result {
-                    stream.do_send(TargetStreamMessage {
-                        message: StreamMessage {
-                            id: sent_msg.id,
-                            user_id: sent_msg.from_user,
-                            quote_id: sent_msg.quote_id,
-                            send_time: sent_msg.send_time,
-                            message_type: sent_msg.message_type,
-                            message: sent_msg.message.clone(),
-                        },
-                        user_id: model.to_user,
-                    });
-                }
-                result
-            })
-            .await
-            {
-                Ok(_) => ResultModel {
-                    success: true,
-                    code: 200,
-                    data: None,
-                    message: None,
-                },
-                Err(BlockingError::Error(e)) => ResultModel {
-                    success: false,
-                    code: 500,
-                    data: None,
-                    message: Some(e.to_string()),
-                },
-                Err(BlockingError::Canceled) => ResultModel {
-                    success: false,
-                    code: 500,
-                    data: None,
-                    message: Some("Operation has been cancelled.".to_string()),
-                },
+            diesel::update(member)
+                .set(schema::conversation_members::dsl::last_read_id.eq(&up_to_id))
+                .execute(conn)?;
+            let read = schema::messages::dsl::messages
+                .filter(
+                    schema::messages::dsl::conversation_id
+                        .eq(&target_conversation_id)
+                        .and(schema::messages::dsl::id.gt(&last_read_id))
+                        .and(schema::messages::dsl::id.le(&up_to_id))
+                        .and(schema::messages::dsl::from_user.ne(&self_user_id)),
+                )
+                .select((schema::messages::dsl::id, schema::messages::dsl::from_user))
+                .load::<(i32, i32)>(conn)?;
+            notify_read(
+                stream,
+                self_user_id,
+                Some(target_conversation_id),
+                now,
+                read,
+            );
+            Ok(())
+        }
+        _ => Err(AppError::BadRequest(
+            "Exactly one of userId and conversationId is required.".to_string(),
+        )),
+    }
+}
+
+/// Marks a single message visible to `self_user_id` as read. Reading a group
+/// message marks the conversation as read up to it.
+fn mark_read(
+    conn: &PgConnection,
+    stream: &Addr<MessageStreamServer>,
+    self_user_id: i32,
+    msg_id: i32,
+) -> Result<(), AppError> {
+    let target = schema::messages::dsl::messages
+        .filter(schema::messages::dsl::id.eq(&msg_id))
+        .first::<schema::Message>(conn)
+        .optional()?
+        .ok_or_else(message_not_found)?;
+    match target.conversation_id {
+        Some(target_conversation_id) => mark_conversation_read(
+            conn,
+            stream,
+            self_user_id,
+            &ReadConversationModel {
+                user_id: None,
+                conversation_id: Some(target_conversation_id),
+                up_to_id: msg_id,
+            },
+        ),
+        None if target.to_user == Some(self_user_id) => {
+            if target.read_time.is_none() {
+                let now = Utc::now().naive_utc();
+                diesel::update(
+                    schema::messages::dsl::messages.filter(schema::messages::dsl::id.eq(&msg_id)),
+                )
+                .set(schema::messages::dsl::read_time.eq(&now))
+                .execute(conn)?;
+                notify_read(
+                    stream,
+                    self_user_id,
+                    None,
+                    now,
+                    vec![(msg_id, target.from_user)],
+                );
             }
+            Ok(())
         }
-        None => ResultModel {
-            success: false,
-            code: 401,
-            data: None,
-            message: Some("Not logged in.".to_string()),
-        },
+        None if target.from_user == self_user_id => Ok(()),
+        None => Err(message_not_found()),
+    }
+}
+
+/// Loads a message if `self_user_id` took part in its conversation.
+fn visible_message(
+    conn: &PgConnection,
+    self_user_id: i32,
+    msg_id: i32,
+) -> Result<schema::Message, AppError> {
+    let target = schema::messages::dsl::messages
+        .filter(schema::messages::dsl::id.eq(&msg_id))
+        .first::<schema::Message>(conn)
+        .optional()?
+        .ok_or_else(message_not_found)?;
+    let visible = match target.conversation_id {
+        Some(target_conversation_id) => {
+            member_ids(conn, target_conversation_id)?.contains(&self_user_id)
+        }
+        None => target.from_user == self_user_id || target.to_user == Some(self_user_id),
+    };
+    if visible {
+        Ok(target)
+    } else {
+        Err(message_not_found())
     }
 }
 
+/// The participants of a message's conversation other than `self_user_id`.
+fn other_participants(
+    conn: &PgConnection,
+    self_user_id: i32,
+    target: &schema::Message,
+) -> QueryResult<Vec<i32>> {
+    Ok(match target.conversation_id {
+        Some(target_conversation_id) => member_ids(conn, target_conversation_id)?,
+        None => vec![target.from_user]
+            .into_iter()
+            .chain(target.to_user)
+            .collect::<Vec<_>>(),
+    }
+    .into_iter()
+    .filter(|member| *member != self_user_id)
+    .collect::<Vec<_>>())
+}
+
+/// Replaces the body of a message sent by `self_user_id`, keeping the previous body
+/// as a revision, and pushes the new body to the other participants.
+fn edit_message(
+    conn: &PgConnection,
+    stream: &Addr<MessageStreamServer>,
+    self_user_id: i32,
+    msg_id: i32,
+    model: &EditMessageModel,
+) -> Result<message::Message, AppError> {
+    if model.message.trim().is_empty() {
+        return Err(AppError::BadRequest("Message cannot be empty.".to_string()));
+    }
+    let now = Utc::now().naive_utc();
+    let edited = conn
+        .transaction::<_, diesel::result::Error, _>(|| {
+            let target = schema::messages::dsl::messages
+                .filter(
+                    schema::messages::dsl::id
+                        .eq(&msg_id)
+                        .and(schema::messages::dsl::from_user.eq(&self_user_id))
+                        .and(schema::messages::dsl::recalled_time.is_null()),
+                )
+                .for_update()
+                .first::<schema::Message>(conn)?;
+            if target.message_type != MESSAGE_TYPE_TEXT {
+                return Err(diesel::result::Error::RollbackTransaction);
+            }
+            diesel::insert_into(schema::message_revisions::dsl::message_revisions)
+                .values((
+                    schema::message_revisions::dsl::message_id.eq(&target.id),
+                    schema::message_revisions::dsl::message.eq(&target.message),
+                    schema::message_revisions::dsl::create_time
+                        .eq(target.edited_time.unwrap_or(target.send_time)),
+                ))
+                .execute(conn)?;
+            diesel::update(
+                schema::messages::dsl::messages.filter(schema::messages::dsl::id.eq(&msg_id)),
+            )
+            .set((
+                schema::messages::dsl::message.eq(&model.message),
+                schema::messages::dsl::edited_time.eq(&now),
+            ))
+            .get_result::<schema::Message>(conn)
+        })
+        .map_err(|e| match e {
+            diesel::result::Error::NotFound => message_not_found(),
+            diesel::result::Error::RollbackTransaction => {
+                AppError::BadRequest("Only text messages can be edited.".to_string())
+            }
+            e => e.into(),
+        })?;
+    stream.do_send(TargetStreamMessage {
+        user_ids: other_participants(conn, self_user_id, &edited)?,
+        message: StreamEvent::Edited(EditedEvent {
+            id: edited.id,
+            user_id: self_user_id,
+            conversation_id: edited.conversation_id,
+            message: edited.message.clone(),
+            edited_time: now,
+        }),
+    });
+    Ok(to_message_models(conn, self_user_id, vec![edited])?.remove(0))
+}
+
+/// Tombstones a message sent by `self_user_id` within the recall window: the body,
+/// revisions, reactions and attachments are dropped and the other participants are notified.
+/// Returns the storage keys of the removed attachments.
+fn recall_message(
+    conn: &PgConnection,
+    stream: &Addr<MessageStreamServer>,
+    settings: &MessageSettings,
+    self_user_id: i32,
+    msg_id: i32,
+) -> Result<Vec<String>, AppError> {
+    let now = Utc::now().naive_utc();
+    let (recalled, removed) = conn
+        .transaction::<_, diesel::result::Error, _>(|| {
+            let target = schema::messages::dsl::messages
+                .filter(
+                    schema::messages::dsl::id
+                        .eq(&msg_id)
+                        .and(schema::messages::dsl::from_user.eq(&self_user_id))
+                        .and(schema::messages::dsl::recalled_time.is_null()),
+                )
+                .for_update()
+                .first::<schema::Message>(conn)?;
+            if now - target.send_time > settings.recall_window {
+                return Err(diesel::result::Error::RollbackTransaction);
+            }
+            diesel::delete(
+                schema::message_revisions::dsl::message_revisions
+                    .filter(schema::message_revisions::dsl::message_id.eq(&msg_id)),
+            )
+            .execute(conn)?;
+            diesel::delete(
+                schema::message_reactions::dsl::message_reactions
+                    .filter(schema::message_reactions::dsl::message_id.eq(&msg_id)),
+            )
+            .execute(conn)?;
+            let removed = diesel::delete(
+                schema::attachments::dsl::attachments
+                    .filter(schema::attachments::dsl::message_id.eq(&msg_id)),
+            )
+            .returning(schema::attachments::dsl::storage_key)
+            .get_results::<String>(conn)?;
+            let recalled = diesel::update(
+                schema::messages::dsl::messages.filter(schema::messages::dsl::id.eq(&msg_id)),
+            )
+            .set((
+                schema::messages::dsl::message.eq(""),
+                schema::messages::dsl::recalled_time.eq(&now),
+            ))
+            .get_result::<schema::Message>(conn)?;
+            Ok((recalled, removed))
+        })
+        .map_err(|e| match e {
+            diesel::result::Error::NotFound => message_not_found(),
+            diesel::result::Error::RollbackTransaction => {
+                AppError::Forbidden("Message can no longer be recalled.".to_string())
+            }
+            e => e.into(),
+        })?;
+    stream.do_send(TargetStreamMessage {
+        user_ids: other_participants(conn, self_user_id, &recalled)?,
+        message: StreamEvent::Recalled(RecalledEvent {
+            id: recalled.id,
+            user_id: self_user_id,
+            conversation_id: recalled.conversation_id,
+            recalled_time: now,
+        }),
+    });
+    Ok(removed)
+}
+
+/// Adds or removes a reaction of `self_user_id` on a message they can see, and tells
+/// the other participants about it.
+fn set_reaction(
+    conn: &PgConnection,
+    stream: &Addr<MessageStreamServer>,
+    self_user_id: i32,
+    msg_id: i32,
+    emoji: String,
+    added: bool,
+) -> Result<(), AppError> {
+    if emoji.is_empty()
+        || emoji.len() > MAX_EMOJI_LENGTH
+        || emoji.chars().any(|c| c.is_whitespace() || c.is_control())
+    {
+        return Err(AppError::BadRequest("Invalid emoji.".to_string()));
+    }
+    let target = visible_message(conn, self_user_id, msg_id)?;
+    if target.recalled_time.is_some() {
+        return Err(AppError::BadRequest(
+            "Message has been recalled.".to_string(),
+        ));
+    }
+    let changed = if added {
+        diesel::insert_into(schema::message_reactions::dsl::message_reactions)
+            .values((
+                schema::message_reactions::dsl::message_id.eq(&msg_id),
+                schema::message_reactions::dsl::user_id.eq(&self_user_id),
+                schema::message_reactions::dsl::emoji.eq(&emoji),
+                schema::message_reactions::dsl::create_time.eq(Utc::now().naive_utc()),
+            ))
+            .on_conflict_do_nothing()
+            .execute(conn)
+    } else {
+        diesel::delete(
+            schema::message_reactions::dsl::message_reactions.filter(
+                schema::message_reactions::dsl::message_id
+                    .eq(&msg_id)
+                    .and(schema::message_reactions::dsl::user_id.eq(&self_user_id))
+                    .and(schema::message_reactions::dsl::emoji.eq(&emoji)),
+            ),
+        )
+        .execute(conn)
+    }?;
+    if changed > 0 {
+        stream.do_send(TargetStreamMessage {
+            user_ids: other_participants(conn, self_user_id, &target)?,
+            message: StreamEvent::Reaction(ReactionEvent {
+                message_id: msg_id,
+                user_id: self_user_id,
+                conversation_id: target.conversation_id,
+                emoji,
+                added,
+            }),
+        });
+    }
+    Ok(())
+}
+
+fn typing_target(to_user: Option<i32>, conversation_id: Option<i32>) -> Option<TypingTarget> {
+    match (to_user, conversation_id) {
+        (Some(user_id), None) => Some(TypingTarget::User(user_id)),
+        (None, Some(conversation_id)) => Some(TypingTarget::Conversation(conversation_id)),
+        _ => None,
+    }
+}
+
+/// Resolves who should see a typing notification from `self_user_id`.
+fn typing_recipients(
+    conn: &PgConnection,
+    self_user_id: i32,
+    target: TypingTarget,
+) -> Result<Vec<i32>, AppError> {
+    match target {
+        TypingTarget::User(target_user_id) if target_user_id != self_user_id => {
+            if blocked_between(conn, self_user_id, target_user_id)? {
+                Err(AppError::Forbidden(
+                    "Cannot send message to this user.".to_string(),
+                ))
+            } else {
+                Ok(vec![target_user_id])
+            }
+        }
+        TypingTarget::Conversation(target_conversation_id) => {
+            let members = member_ids(conn, target_conversation_id)?;
+            if members.contains(&self_user_id) {
+                Ok(members
+                    .into_iter()
+                    .filter(|member| *member != self_user_id)
+                    .collect::<Vec<_>>())
+            } else {
+                Err(AppError::Forbidden(
+                    "Not a member of this conversation.".to_string(),
+                ))
+            }
+        }
+        TypingTarget::User(_) => Err(AppError::BadRequest(
+            "Cannot send message to yourself.".to_string(),
+        )),
+    }
+}
+
+pub async fn send(
+    web::Json(model): web::Json<SendMessageModel>,
+    auth: AuthUser,
+    pool: web::Data<DbPool>,
+    stream: web::Data<Addr<MessageStreamServer>>,
+) -> Result<impl Responder, AppError> {
+    let conn = pool.get()?;
+    let self_user_id = auth.user_id;
+    web::block(move || deliver_message(&conn, &stream, self_user_id, &model)).await?;
+    Ok(ResultModel::empty())
+}
+
+#[derive(Clone)]
+struct TypingState {
+    recipients: Vec<i32>,
+    relayed: Instant,
+    expiry: SpawnHandle,
+}
+
 #[derive(Clone)]
 pub struct MessageStreamServer {
-    sessions: HashMap<i32, Recipient<StreamMessage>>,
+    sessions: HashMap<i32, HashMap<usize, Recipient<StreamEvent>>>,
+    away_sessions: HashSet<usize>,
+    /// The login session each stream was opened with.
+    login_sessions: HashMap<usize, i32>,
+    typing: HashMap<(i32, TypingTarget), TypingState>,
+    next_session_id: usize,
+    pool: DbPool,
 }
 
 impl MessageStreamServer {
-    pub fn new() -> Self {
+    pub fn new(pool: DbPool) -> Self {
         Self {
-            sessions: HashMap::<i32, Recipient<StreamMessage>>::new(),
+            sessions: HashMap::<i32, HashMap<usize, Recipient<StreamEvent>>>::new(),
+            away_sessions: HashSet::new(),
+            login_sessions: HashMap::new(),
+            typing: HashMap::new(),
+            next_session_id: 0,
+            pool,
+        }
+    }
+
+    fn send_typing(
+        &self,
+        (user_id, target): (i32, TypingTarget),
+        recipients: &[i32],
+        typing: bool,
+    ) {
+        let event = StreamEvent::Typing(TypingEvent {
+            user_id,
+            conversation_id: match target {
+                TypingTarget::User(_) => None,
+                TypingTarget::Conversation(conversation_id) => Some(conversation_id),
+            },
+            typing,
+        });
+        for recipient in recipients {
+            self.send_to_user(*recipient, &event);
+        }
+    }
+
+    fn stop_typing(&mut self, key: (i32, TypingTarget), ctx: &mut Context<Self>) {
+        if let Some(state) = self.typing.remove(&key) {
+            ctx.cancel_future(state.expiry);
+            self.send_typing(key, &state.recipients, false);
+        }
+    }
+
+    /// A user is online while any of their sessions is, away while all of them are
+    /// away, and offline once the last one is gone.
+    fn presence(&self, user_id: i32) -> PresenceStatus {
+        match self.sessions.get(&user_id) {
+            None => PresenceStatus::Offline,
+            Some(sessions) if sessions.keys().all(|id| self.away_sessions.contains(id)) => {
+                PresenceStatus::Away
+            }
+            Some(_) => PresenceStatus::Online,
+        }
+    }
+
+    /// Records `last_seen` if `user_id` has gone offline and pushes their presence to
+    /// their friends. Users hiding their presence are left out unless `force` is set,
+    /// in which case they are announced as offline.
+    fn broadcast_presence(&self, user_id: i32, force: bool, ctx: &mut Context<Self>) {
+        use schema::{friends, users};
+
+        let offline = self.presence(user_id) == PresenceStatus::Offline;
+        let pool = self.pool.clone();
+        web::block(move || -> Result<_, String> {
+            let conn = pool.get().map_err(|e| e.to_string())?;
+            if offline {
+                diesel::update(users::table.find(user_id))
+                    .set(users::last_seen.eq(Utc::now().naive_utc()))
+                    .execute(&conn)
+                    .map_err(|e| e.to_string())?;
+            }
+            let (hide_presence, last_seen) = users::table
+                .find(user_id)
+                .select((users::hide_presence, users::last_seen))
+                .first::<(bool, Option<NaiveDateTime>)>(&conn)
+                .map_err(|e| e.to_string())?;
+            let friend_ids = friends::table
+                .filter(friends::user_id.eq(user_id))
+                .select(friends::friend_user_id)
+                .load::<i32>(&conn)
+                .map_err(|e| e.to_string())?;
+            Ok((hide_presence, last_seen, friend_ids))
+        })
+        .into_actor(self)
+        .map(move |res, act, _ctx| {
+            // Presence is best effort; a failed lookup only means friends miss
+            // this change.
+            if let Ok((hide_presence, last_seen, friend_ids)) = res {
+                if hide_presence && !force {
+                    return;
+                }
+                // Sessions may have come or gone while the lookup ran, so announce
+                // the status as it is now.
+                let event = StreamEvent::Presence(if hide_presence {
+                    PresenceEvent {
+                        user_id,
+                        status: PresenceStatus::Offline,
+                        last_seen: None,
+                    }
+                } else {
+                    PresenceEvent {
+                        user_id,
+                        status: act.presence(user_id),
+                        last_seen,
+                    }
+                });
+                for friend_id in friend_ids {
+                    act.send_to_user(friend_id, &event);
+                }
+            }
+        })
+        .spawn(ctx);
+    }
+
+    fn send_to_user(&self, user_id: i32, message: &StreamEvent) {
+        if let Some(sessions) = self.sessions.get(&user_id) {
+            for session in sessions.values() {
+                // A closed mailbox only means the session is going away; its
+                // `Disconnect` will clean it up.
+                let _ = session.do_send(message.clone());
+            }
         }
     }
 }
@@ -290,42 +1452,405 @@ impl Actor for MessageStreamServer {
 }
 
 impl Handler<Connect> for MessageStreamServer {
-    type Result = ();
+    type Result = usize;
 
-    fn handle(&mut self, msg: Connect, _ctx: &mut Self::Context) -> Self::Result {
-        self.sessions.insert(msg.user_id, msg.addr);
+    fn handle(&mut self, msg: Connect, ctx: &mut Self::Context) -> Self::Result {
+        let session_id = self.next_session_id;
+        self.next_session_id = self.next_session_id.wrapping_add(1);
+        let before = self.presence(msg.user_id);
+        self.sessions
+            .entry(msg.user_id)
+            .or_default()
+            .insert(session_id, msg.addr);
+        self.login_sessions.insert(session_id, msg.login_session_id);
+        if self.presence(msg.user_id) != before {
+            self.broadcast_presence(msg.user_id, false, ctx);
+        }
+        session_id
     }
 }
 
 impl Handler<Disconnect> for MessageStreamServer {
     type Result = ();
 
-    fn handle(&mut self, msg: Disconnect, _ctx: &mut Self::Context) -> Self::Result {
-        self.sessions.remove(&msg.user_id);
+    fn handle(&mut self, msg: Disconnect, ctx: &mut Self::Context) -> Self::Result {
+        let before = self.presence(msg.user_id);
+        self.away_sessions.remove(&msg.session_id);
+        self.login_sessions.remove(&msg.session_id);
+        if let Some(sessions) = self.sessions.get_mut(&msg.user_id) {
+            sessions.remove(&msg.session_id);
+            if sessions.is_empty() {
+                self.sessions.remove(&msg.user_id);
+            }
+        }
+        if self.presence(msg.user_id) != before {
+            if !self.sessions.contains_key(&msg.user_id) {
+                let keys = self
+                    .typing
+                    .keys()
+                    .filter(|key| key.0 == msg.user_id)
+                    .copied()
+                    .collect::<Vec<_>>();
+                for key in keys {
+                    self.stop_typing(key, ctx);
+                }
+            }
+            self.broadcast_presence(msg.user_id, false, ctx);
+        }
+    }
+}
+
+impl Handler<StartTyping> for MessageStreamServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: StartTyping, ctx: &mut Self::Context) -> Self::Result {
+        let key = (msg.user_id, msg.target);
+        let expiry = ctx.run_later(TYPING_TIMEOUT, move |act, ctx| act.stop_typing(key, ctx));
+        let relay = match self.typing.get_mut(&key) {
+            Some(state) => {
+                ctx.cancel_future(state.expiry);
+                state.expiry = expiry;
+                state.recipients = msg.recipients;
+                // Peers already show the indicator, so only refresh it now and then.
+                let relay = state.relayed.elapsed() >= TYPING_THROTTLE;
+                if relay {
+                    state.relayed = Instant::now();
+                }
+                relay
+            }
+            None => {
+                self.typing.insert(
+                    key,
+                    TypingState {
+                        recipients: msg.recipients,
+                        relayed: Instant::now(),
+                        expiry,
+                    },
+                );
+                true
+            }
+        };
+        if relay {
+            self.send_typing(key, &self.typing[&key].recipients, true);
+        }
+    }
+}
+
+impl Handler<StopTyping> for MessageStreamServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: StopTyping, ctx: &mut Self::Context) -> Self::Result {
+        self.stop_typing((msg.user_id, msg.target), ctx);
+    }
+}
+
+impl Handler<RevokeSessions> for MessageStreamServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: RevokeSessions, _ctx: &mut Self::Context) -> Self::Result {
+        if let Some(sessions) = self.sessions.get(&msg.user_id) {
+            for (session_id, session) in sessions {
+                let login_session_id = self.login_sessions.get(session_id);
+                if matches!(login_session_id, Some(id) if msg.login_session_ids.contains(id)) {
+                    // The session closes itself, and its `Disconnect` cleans up.
+                    let _ = session.do_send(StreamEvent::SessionRevoked);
+                }
+            }
+        }
+    }
+}
+
+impl Handler<SetAway> for MessageStreamServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetAway, ctx: &mut Self::Context) -> Self::Result {
+        let before = self.presence(msg.user_id);
+        if msg.away {
+            self.away_sessions.insert(msg.session_id);
+        } else {
+            self.away_sessions.remove(&msg.session_id);
+        }
+        if self.presence(msg.user_id) != before {
+            self.broadcast_presence(msg.user_id, false, ctx);
+        }
+    }
+}
+
+impl Handler<RefreshPresence> for MessageStreamServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: RefreshPresence, ctx: &mut Self::Context) -> Self::Result {
+        self.broadcast_presence(msg.user_id, true, ctx);
+    }
+}
+
+impl Handler<GetPresence> for MessageStreamServer {
+    type Result = MessageResult<GetPresence>;
+
+    fn handle(&mut self, msg: GetPresence, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(
+            msg.user_ids
+                .into_iter()
+                .map(|user_id| self.presence(user_id))
+                .collect(),
+        )
     }
 }
 
 #[derive(actix::Message)]
 #[rtype(result = "()")]
-struct TargetStreamMessage {
-    pub user_id: i32,
-    pub message: StreamMessage,
+pub struct TargetStreamMessage {
+    pub user_ids: Vec<i32>,
+    pub message: StreamEvent,
 }
 
 impl Handler<TargetStreamMessage> for MessageStreamServer {
     type Result = ();
 
     fn handle(&mut self, msg: TargetStreamMessage, _ctx: &mut Self::Context) -> Self::Result {
-        match self.sessions.get(&msg.user_id) {
-            Some(session) => session.do_send(msg.message).unwrap(),
-            None => (),
-        };
+        for user_id in msg.user_ids {
+            self.send_to_user(user_id, &msg.message);
+        }
     }
 }
 
 struct MessageStreamSession {
+    pub session_id: usize,
     pub user_id: i32,
+    pub login_session_id: i32,
     pub addr: Addr<MessageStreamServer>,
+    pub pool: DbPool,
+    pub resume_from: Option<i32>,
+    /// At most this many messages are replayed; the rest are paged through `/sync`.
+    pub replay_limit: i64,
+    pub replayed: HashSet<i32>,
+    pub typing_recipients: HashMap<TypingTarget, (Vec<i32>, Instant)>,
+}
+
+impl MessageStreamSession {
+    fn reply(ctx: &mut ws::WebsocketContext<Self>, event: StreamEvent) {
+        ctx.text(serde_json::to_string(&event).unwrap());
+    }
+
+    /// Replays every message newer than `resume_from`. The context waits on this, so
+    /// live messages queue up behind the replay; any of them already replayed are
+    /// skipped when they arrive.
+    fn replay(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        let last_id = match self.resume_from {
+            Some(last_id) => last_id,
+            None => return,
+        };
+        let pool = self.pool.clone();
+        let self_user_id = self.user_id;
+        let limit = self.replay_limit;
+        web::block(move || -> Result<_, AppError> {
+            let conn = pool.get()?;
+            let mut result =
+                messages_since(&conn, self_user_id, Some(last_id), None, Some(limit + 1))?;
+            let has_more = result.len() as i64 > limit;
+            result.truncate(limit as usize);
+            let attachments = load_attachments(
+                &conn,
+                &result.iter().map(|item| item.id).collect::<Vec<_>>(),
+            )?;
+            Ok((result, attachments, has_more))
+        })
+        .into_actor(self)
+        .map(move |res, act, ctx| match res {
+            Ok((result, mut attachments, has_more)) => {
+                for item in &result {
+                    act.replayed.insert(item.id);
+                    let item_attachments = attachments.remove(&item.id).unwrap_or_default();
+                    Self::reply(
+                        ctx,
+                        StreamEvent::Message(to_stream_message(item, item_attachments)),
+                    );
+                }
+                Self::reply(
+                    ctx,
+                    StreamEvent::Synced(SyncedEvent {
+                        last_id: result.last().map(|item| item.id).or(Some(last_id)),
+                        has_more,
+                    }),
+                );
+            }
+            Err(e) => {
+                Self::reply(ctx, Self::error_event(None, e));
+                ctx.stop();
+            }
+        })
+        .wait(ctx);
+    }
+
+    /// Runs `action` on the blocking pool and answers the frame with an ack
+    /// carrying its result, or an error frame.
+    fn spawn_request<F>(
+        &self,
+        request_id: Option<serde_json::Value>,
+        ctx: &mut ws::WebsocketContext<Self>,
+        action: F,
+    ) where
+        F: FnOnce(&PgConnection) -> Result<Option<serde_json::Value>, AppError> + Send + 'static,
+    {
+        let pool = self.pool.clone();
+        web::block(move || {
+            let conn = pool.get()?;
+            action(&conn)
+        })
+        .into_actor(self)
+        .map(move |res, _act, ctx| {
+            Self::reply(
+                ctx,
+                match res {
+                    Ok(data) => StreamEvent::Ack(StreamAck { request_id, data }),
+                    Err(e) => Self::error_event(request_id, e),
+                },
+            )
+        })
+        .spawn(ctx);
+    }
+
+    fn error_event(request_id: Option<serde_json::Value>, e: impl Into<AppError>) -> StreamEvent {
+        let e = e.into();
+        e.log();
+        StreamEvent::Error(StreamError {
+            request_id,
+            code: e.status_code().as_u16(),
+            message: e.client_message(),
+            error_code: e.error_code(),
+        })
+    }
+
+    /// Hands a typing notification to the stream server. Recipients are looked up
+    /// once per target and reused for a while, so a client refreshing the indicator
+    /// as the user types doesn't hit the database.
+    fn typing(
+        &mut self,
+        request_id: Option<serde_json::Value>,
+        model: TypingModel,
+        typing: bool,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        let target = match typing_target(model.to_user, model.conversation_id) {
+            Some(target) => target,
+            None => {
+                return Self::reply(
+                    ctx,
+                    Self::error_event(
+                        request_id,
+                        AppError::BadRequest(
+                            "Exactly one of toUser and conversationId is required.".to_string(),
+                        ),
+                    ),
+                )
+            }
+        };
+        let user_id = self.user_id;
+        if !typing {
+            self.addr.do_send(StopTyping { user_id, target });
+            return Self::reply(
+                ctx,
+                StreamEvent::Ack(StreamAck {
+                    request_id,
+                    data: None,
+                }),
+            );
+        }
+        if let Some((recipients, resolved)) = self.typing_recipients.get(&target) {
+            if resolved.elapsed() < TYPING_RECIPIENTS_TTL {
+                self.addr.do_send(StartTyping {
+                    user_id,
+                    target,
+                    recipients: recipients.clone(),
+                });
+                return Self::reply(
+                    ctx,
+                    StreamEvent::Ack(StreamAck {
+                        request_id,
+                        data: None,
+                    }),
+                );
+            }
+        }
+        let pool = self.pool.clone();
+        web::block(move || {
+            let conn = pool.get()?;
+            typing_recipients(&conn, user_id, target)
+        })
+        .into_actor(self)
+        .map(move |res, act, ctx| match res {
+            Ok(recipients) => {
+                act.typing_recipients
+                    .insert(target, (recipients.clone(), Instant::now()));
+                act.addr.do_send(StartTyping {
+                    user_id,
+                    target,
+                    recipients,
+                });
+                Self::reply(
+                    ctx,
+                    StreamEvent::Ack(StreamAck {
+                        request_id,
+                        data: None,
+                    }),
+                )
+            }
+            Err(e) => Self::reply(ctx, Self::error_event(request_id, e)),
+        })
+        .spawn(ctx);
+    }
+
+    fn handle_request(&mut self, request: StreamRequest, ctx: &mut ws::WebsocketContext<Self>) {
+        let self_user_id = self.user_id;
+        let stream = self.addr.clone();
+        match request.action {
+            StreamAction::Send(model) => self.spawn_request(request.request_id, ctx, move |conn| {
+                deliver_message(conn, &stream, self_user_id, &model)
+                    .map(|sent_msg| serde_json::to_value(sent_msg).ok())
+            }),
+            StreamAction::Read(model) => self.spawn_request(request.request_id, ctx, move |conn| {
+                mark_read(conn, &stream, self_user_id, model.message_id).map(|_| None)
+            }),
+            StreamAction::TypingStart(model) => self.typing(request.request_id, model, true, ctx),
+            StreamAction::TypingStop(model) => self.typing(request.request_id, model, false, ctx),
+            StreamAction::Presence(model) => {
+                let away = match model.status {
+                    PresenceStatus::Online => false,
+                    PresenceStatus::Away => true,
+                    PresenceStatus::Offline => {
+                        return Self::reply(
+                            ctx,
+                            Self::error_event(
+                                request.request_id,
+                                AppError::BadRequest(
+                                    "Presence can only be online or away.".to_string(),
+                                ),
+                            ),
+                        )
+                    }
+                };
+                self.addr.do_send(SetAway {
+                    session_id: self.session_id,
+                    user_id: self_user_id,
+                    away,
+                });
+                Self::reply(
+                    ctx,
+                    StreamEvent::Ack(StreamAck {
+                        request_id: request.request_id,
+                        data: None,
+                    }),
+                )
+            }
+            StreamAction::Ping => Self::reply(
+                ctx,
+                StreamEvent::Ack(StreamAck {
+                    request_id: request.request_id,
+                    data: None,
+                }),
+            ),
+        }
+    }
 }
 
 impl Actor for MessageStreamSession {
@@ -336,12 +1861,16 @@ impl Actor for MessageStreamSession {
         self.addr
             .send(Connect {
                 user_id: self.user_id,
+                login_session_id: self.login_session_id,
                 addr: addr.recipient(),
             })
             .into_actor(self)
-            .then(|res, _act, ctx| {
+            .then(|res, act, ctx| {
                 match res {
-                    Ok(_) => (),
+                    Ok(session_id) => {
+                        act.session_id = session_id;
+                        act.replay(ctx);
+                    }
                     _ => ctx.stop(),
                 }
                 fut::ready(())
@@ -351,17 +1880,31 @@ impl Actor for MessageStreamSession {
 
     fn stopping(&mut self, _: &mut Self::Context) -> Running {
         self.addr.do_send(Disconnect {
+            session_id: self.session_id,
             user_id: self.user_id,
         });
         Running::Stop
     }
 }
 
-impl Handler<StreamMessage> for MessageStreamSession {
+impl Handler<StreamEvent> for MessageStreamSession {
     type Result = ();
 
-    fn handle(&mut self, msg: StreamMessage, ctx: &mut Self::Context) {
-        ctx.text(serde_json::to_string(&msg).unwrap());
+    fn handle(&mut self, msg: StreamEvent, ctx: &mut Self::Context) {
+        match msg {
+            StreamEvent::Message(ref message) if self.replayed.contains(&message.id) => return,
+            StreamEvent::SessionRevoked => {
+                Self::reply(ctx, msg);
+                ctx.close(Some(ws::CloseReason {
+                    code: ws::CloseCode::Policy,
+                    description: Some("Session revoked.".to_string()),
+                }));
+                ctx.stop();
+                return;
+            }
+            _ => (),
+        }
+        Self::reply(ctx, msg);
     }
 }
 
@@ -369,6 +1912,17 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for MessageStreamSess
     fn handle(&mut self, item: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
         match item {
             Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
+            Ok(ws::Message::Text(text)) => match serde_json::from_str::<StreamRequest>(&text) {
+                Ok(request) => self.handle_request(request, ctx),
+                Err(e) => Self::reply(
+                    ctx,
+                    Self::error_event(None, AppError::BadRequest(e.to_string())),
+                ),
+            },
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
             _ => (),
         }
     }
@@ -376,85 +1930,442 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for MessageStreamSess
 
 pub async fn stream(
     req: HttpRequest,
-    identity: Identity,
+    web::Query(query): web::Query<StreamModel>,
     payload: web::Payload,
+    pool: web::Data<DbPool>,
     stream: web::Data<Addr<MessageStreamServer>>,
+    paging: web::Data<PagingConfig>,
 ) -> Result<HttpResponse, Error> {
-    match identity.identity() {
-        Some(user_id_str) => {
-            let self_user_id = user_id_str.parse::<i32>().unwrap();
-            let resp = ws::start(
-                MessageStreamSession {
-                    user_id: self_user_id,
-                    addr: stream.get_ref().clone(),
-                },
-                &req,
-                payload,
-            );
-            resp
-        }
-        None => Ok(HttpResponse::Unauthorized()
-            .content_type("application/json")
-            .body(
-                serde_json::to_string(&ResultModel::<String> {
-                    success: false,
-                    code: 401,
-                    data: None,
-                    message: Some("Not logged in.".to_string()),
-                })
-                .unwrap(),
-            )
-            .into()),
-    }
+    let auth = AuthUser::from_upgrade(&req, query.access_token.as_deref()).await?;
+    ws::start(
+        MessageStreamSession {
+            session_id: 0,
+            user_id: auth.user_id,
+            login_session_id: auth.session_id,
+            addr: stream.get_ref().clone(),
+            pool: pool.get_ref().clone(),
+            resume_from: query.last_id,
+            replay_limit: paging.sync_page_size,
+            replayed: HashSet::new(),
+            typing_recipients: HashMap::new(),
+        },
+        &req,
+        payload,
+    )
+}
+
+pub async fn sync(
+    web::Query(query): web::Query<SyncModel>,
+    auth: AuthUser,
+    pool: web::Data<DbPool>,
+    paging: web::Data<PagingConfig>,
+) -> Result<impl Responder, AppError> {
+    let conn = pool.get()?;
+    let self_user_id = auth.user_id;
+    let result = web::block(move || -> QueryResult<SyncResult> {
+        let mut result = messages_since(
+            &conn,
+            self_user_id,
+            query.since_id,
+            query.since_time,
+            Some(paging.sync_page_size + 1),
+        )?;
+        let has_more = result.len() as i64 > paging.sync_page_size;
+        result.truncate(paging.sync_page_size as usize);
+        Ok(SyncResult {
+            messages: to_message_models(&conn, self_user_id, result)?,
+            has_more,
+        })
+    })
+    .await?;
+    Ok(ResultModel::ok(result))
 }
 
 pub async fn set_read(
     web::Path(msg_id): web::Path<i32>,
-    identity: Identity,
+    auth: AuthUser,
     pool: web::Data<DbPool>,
-) -> impl Responder {
-    let conn = pool.get().expect("Failed to get connection from pool.");
-    match identity.identity() {
-        Some(user_id_str) => {
-            let self_user_id = user_id_str.parse::<i32>().unwrap();
-            match web::block(move || {
-                diesel::update(
-                    schema::messages::dsl::messages.filter(
-                        schema::messages::dsl::id
-                            .eq(&msg_id)
-                            .and(schema::messages::dsl::to_user.eq(&self_user_id)),
-                    ),
-                )
-                .set(schema::messages::dsl::read_time.eq(Utc::now().naive_utc()))
-                .execute(&conn)
+    stream: web::Data<Addr<MessageStreamServer>>,
+) -> Result<impl Responder, AppError> {
+    let conn = pool.get()?;
+    let self_user_id = auth.user_id;
+    web::block(move || mark_read(&conn, &stream, self_user_id, msg_id)).await?;
+    Ok(ResultModel::empty())
+}
+
+pub async fn edit(
+    web::Path(msg_id): web::Path<i32>,
+    web::Json(model): web::Json<EditMessageModel>,
+    auth: AuthUser,
+    pool: web::Data<DbPool>,
+    stream: web::Data<Addr<MessageStreamServer>>,
+) -> Result<impl Responder, AppError> {
+    let conn = pool.get()?;
+    let self_user_id = auth.user_id;
+    let result =
+        web::block(move || edit_message(&conn, &stream, self_user_id, msg_id, &model)).await?;
+    Ok(ResultModel::ok(result))
+}
+
+pub async fn revisions(
+    web::Path(msg_id): web::Path<i32>,
+    auth: AuthUser,
+    pool: web::Data<DbPool>,
+) -> Result<impl Responder, AppError> {
+    let conn = pool.get()?;
+    let self_user_id = auth.user_id;
+    let result = web::block(move || -> Result<_, AppError> {
+        visible_message(&conn, self_user_id, msg_id)?;
+        Ok(schema::message_revisions::dsl::message_revisions
+            .filter(schema::message_revisions::dsl::message_id.eq(&msg_id))
+            .order(schema::message_revisions::dsl::id.asc())
+            .load::<schema::MessageRevision>(&conn)?)
+    })
+    .await?;
+    Ok(ResultModel::ok(
+        result
+            .into_iter()
+            .map(|item| MessageRevisionInfo {
+                message: item.message,
+                create_time: item.create_time,
             })
-            .await
-            {
-                Ok(_) => ResultModel::<String> {
-                    success: true,
-                    code: 200,
-                    data: None,
-                    message: None,
-                },
-                Err(BlockingError::Error(e)) => ResultModel {
-                    success: false,
-                    code: 500,
-                    data: None,
-                    message: Some(e.to_string()),
-                },
-                Err(BlockingError::Canceled) => ResultModel {
-                    success: false,
-                    code: 500,
-                    data: None,
-                    message: Some("Operation has been cancelled.".to_string()),
-                },
-            }
+            .collect::<Vec<_>>(),
+    ))
+}
+
+/// Hides a message from the caller's own history only.
+pub async fn delete_for_me(
+    web::Path(msg_id): web::Path<i32>,
+    auth: AuthUser,
+    pool: web::Data<DbPool>,
+) -> Result<impl Responder, AppError> {
+    let conn = pool.get()?;
+    let self_user_id = auth.user_id;
+    web::block(move || -> Result<_, AppError> {
+        visible_message(&conn, self_user_id, msg_id)?;
+        Ok(
+            diesel::insert_into(schema::message_deletions::dsl::message_deletions)
+                .values((
+                    schema::message_deletions::dsl::message_id.eq(&msg_id),
+                    schema::message_deletions::dsl::user_id.eq(&self_user_id),
+                    schema::message_deletions::dsl::delete_time.eq(Utc::now().naive_utc()),
+                ))
+                .on_conflict_do_nothing()
+                .execute(&conn)?,
+        )
+    })
+    .await?;
+    Ok(ResultModel::empty())
+}
+
+pub async fn recall(
+    web::Path(msg_id): web::Path<i32>,
+    auth: AuthUser,
+    pool: web::Data<DbPool>,
+    stream: web::Data<Addr<MessageStreamServer>>,
+    storage: web::Data<Arc<dyn Storage>>,
+    settings: web::Data<MessageSettings>,
+) -> Result<impl Responder, AppError> {
+    let conn = pool.get()?;
+    let self_user_id = auth.user_id;
+    let removed =
+        web::block(move || recall_message(&conn, &stream, &settings, self_user_id, msg_id)).await?;
+    for storage_key in removed {
+        let _ = storage.remove(&storage_key);
+    }
+    Ok(ResultModel::empty())
+}
+
+pub async fn add_reaction(
+    web::Path((msg_id, emoji)): web::Path<(i32, String)>,
+    auth: AuthUser,
+    pool: web::Data<DbPool>,
+    stream: web::Data<Addr<MessageStreamServer>>,
+) -> Result<impl Responder, AppError> {
+    let conn = pool.get()?;
+    let self_user_id = auth.user_id;
+    web::block(move || set_reaction(&conn, &stream, self_user_id, msg_id, emoji, true)).await?;
+    Ok(ResultModel::empty())
+}
+
+pub async fn remove_reaction(
+    web::Path((msg_id, emoji)): web::Path<(i32, String)>,
+    auth: AuthUser,
+    pool: web::Data<DbPool>,
+    stream: web::Data<Addr<MessageStreamServer>>,
+) -> Result<impl Responder, AppError> {
+    let conn = pool.get()?;
+    let self_user_id = auth.user_id;
+    web::block(move || set_reaction(&conn, &stream, self_user_id, msg_id, emoji, false)).await?;
+    Ok(ResultModel::empty())
+}
+
+/// Lists the replies quoting a message, oldest first unless paging with `before`.
+pub async fn replies(
+    web::Path(msg_id): web::Path<i32>,
+    web::Query(query): web::Query<CursorModel>,
+    auth: AuthUser,
+    pool: web::Data<DbPool>,
+    paging: web::Data<PagingConfig>,
+) -> Result<impl Responder, AppError> {
+    let conn = pool.get()?;
+    let self_user_id = auth.user_id;
+    let result = web::block(move || -> Result<_, AppError> {
+        visible_message(&conn, self_user_id, msg_id)?;
+        Ok(message_page(
+            &conn,
+            self_user_id,
+            schema::messages::dsl::messages
+                .filter(schema::messages::dsl::quote_id.eq(&msg_id))
+                .into_boxed(),
+            &query,
+            &paging,
+            true,
+        )?)
+    })
+    .await?;
+    Ok(ResultModel::ok(result))
+}
+
+pub async fn set_read_all(
+    web::Json(model): web::Json<ReadConversationModel>,
+    auth: AuthUser,
+    pool: web::Data<DbPool>,
+    stream: web::Data<Addr<MessageStreamServer>>,
+) -> Result<impl Responder, AppError> {
+    let conn = pool.get()?;
+    let self_user_id = auth.user_id;
+    web::block(move || mark_conversation_read(&conn, &stream, self_user_id, &model)).await?;
+    Ok(ResultModel::empty())
+}
+
+pub async fn conversations(
+    auth: AuthUser,
+    pool: web::Data<DbPool>,
+) -> Result<impl Responder, AppError> {
+    let conn = pool.get()?;
+    let self_user_id = auth.user_id;
+    let result = web::block(move || {
+        schema::conversation_members::dsl::conversation_members
+            .filter(schema::conversation_members::dsl::user_id.eq(&self_user_id))
+            .select(schema::conversation_members::dsl::conversation_id)
+            .load::<i32>(&conn)?
+            .into_iter()
+            .map(|item| conversation_info(&conn, item))
+            .collect::<QueryResult<Vec<_>>>()
+    })
+    .await?;
+    Ok(ResultModel::ok(result))
+}
+
+pub async fn conversation(
+    web::Path(conversation_id): web::Path<i32>,
+    auth: AuthUser,
+    pool: web::Data<DbPool>,
+) -> Result<impl Responder, AppError> {
+    let conn = pool.get()?;
+    let self_user_id = auth.user_id;
+    let info =
+        web::block(move || joined_conversation(&conn, self_user_id, conversation_id)).await?;
+    Ok(ResultModel::ok(info))
+}
+
+pub async fn create_conversation(
+    web::Json(model): web::Json<CreateConversationModel>,
+    auth: AuthUser,
+    pool: web::Data<DbPool>,
+) -> Result<impl Responder, AppError> {
+    if model.name.trim().is_empty() {
+        return Err(AppError::BadRequest(
+            "Conversation name cannot be empty.".to_string(),
+        ));
+    }
+    let conn = pool.get()?;
+    let self_user_id = auth.user_id;
+    let mut members = vec![self_user_id];
+    for member in &model.members {
+        if !members.contains(member) {
+            members.push(*member);
         }
-        None => ResultModel {
-            success: false,
-            code: 401,
-            data: None,
-            message: Some("Not logged in.".to_string()),
-        },
     }
+    let info = web::block(move || -> Result<_, AppError> {
+        let count = schema::users::dsl::users
+            .filter(schema::users::dsl::id.eq_any(&members))
+            .count()
+            .get_result::<i64>(&conn)?;
+        if count != members.len() as i64 {
+            return Err(AppError::BadRequest("User doesn't exists.".to_string()));
+        }
+        let now = Utc::now().naive_utc();
+        Ok(conn.transaction::<_, diesel::result::Error, _>(|| {
+            let created = diesel::insert_into(schema::conversations::dsl::conversations)
+                .values((
+                    schema::conversations::dsl::name.eq(model.name.trim()),
+                    schema::conversations::dsl::owner_id.eq(&self_user_id),
+                    schema::conversations::dsl::create_time.eq(&now),
+                ))
+                .get_result::<schema::Conversation>(&conn)?;
+            let last_read_id = latest_message_id(&conn, created.id)?;
+            diesel::insert_into(schema::conversation_members::dsl::conversation_members)
+                .values(
+                    &members
+                        .iter()
+                        .map(|member| NewConversationMember {
+                            conversation_id: created.id,
+                            user_id: *member,
+                            join_time: now,
+                            last_read_id,
+                        })
+                        .collect::<Vec<_>>(),
+                )
+                .execute(&conn)?;
+            conversation_info(&conn, created.id)
+        })?)
+    })
+    .await?;
+    Ok(ResultModel::ok(info))
+}
+
+pub async fn conversation_history(
+    web::Path(conversation_id): web::Path<i32>,
+    web::Query(query): web::Query<CursorModel>,
+    auth: AuthUser,
+    pool: web::Data<DbPool>,
+    paging: web::Data<PagingConfig>,
+) -> Result<impl Responder, AppError> {
+    let conn = pool.get()?;
+    let self_user_id = auth.user_id;
+    let result = web::block(move || -> Result<_, AppError> {
+        if !member_ids(&conn, conversation_id)?.contains(&self_user_id) {
+            return Err(AppError::NotFound(
+                "Conversation doesn't exists.".to_string(),
+            ));
+        }
+        Ok(message_page(
+            &conn,
+            self_user_id,
+            schema::messages::dsl::messages
+                .filter(schema::messages::dsl::conversation_id.eq(&conversation_id))
+                .into_boxed(),
+            &query,
+            &paging,
+            false,
+        )?)
+    })
+    .await?;
+    Ok(ResultModel::ok(result))
+}
+
+pub async fn invite_member(
+    web::Path((conversation_id, user_id)): web::Path<(i32, i32)>,
+    auth: AuthUser,
+    pool: web::Data<DbPool>,
+) -> Result<impl Responder, AppError> {
+    let conn = pool.get()?;
+    let self_user_id = auth.user_id;
+    web::block(move || -> Result<_, AppError> {
+        let info = joined_conversation(&conn, self_user_id, conversation_id)?;
+        if info.members.contains(&user_id) {
+            return Err(AppError::BadRequest(
+                "Already a member of this conversation.".to_string(),
+            ));
+        }
+        schema::users::dsl::users
+            .filter(schema::users::dsl::id.eq(&user_id))
+            .select(schema::users::dsl::id)
+            .first::<i32>(&conn)
+            .optional()?
+            .ok_or_else(|| AppError::NotFound("User doesn't exists.".to_string()))?;
+        Ok(
+            diesel::insert_into(schema::conversation_members::dsl::conversation_members)
+                .values(&NewConversationMember {
+                    conversation_id,
+                    user_id,
+                    join_time: Utc::now().naive_utc(),
+                    last_read_id: latest_message_id(&conn, conversation_id)?,
+                })
+                .execute(&conn)?,
+        )
+    })
+    .await?;
+    Ok(ResultModel::empty())
+}
+
+pub async fn remove_member(
+    web::Path((conversation_id, user_id)): web::Path<(i32, i32)>,
+    auth: AuthUser,
+    pool: web::Data<DbPool>,
+) -> Result<impl Responder, AppError> {
+    let self_user_id = auth.user_id;
+    if self_user_id == user_id {
+        return Err(AppError::BadRequest(
+            "Cannot remove yourself, leave the conversation instead.".to_string(),
+        ));
+    }
+    let conn = pool.get()?;
+    web::block(move || -> Result<_, AppError> {
+        let info = joined_conversation(&conn, self_user_id, conversation_id)?;
+        if info.owner_id != self_user_id {
+            return Err(AppError::Forbidden(
+                "Only the owner can remove members.".to_string(),
+            ));
+        }
+        if !info.members.contains(&user_id) {
+            return Err(AppError::BadRequest(
+                "Not a member of this conversation.".to_string(),
+            ));
+        }
+        Ok(diesel::delete(
+            schema::conversation_members::dsl::conversation_members.filter(
+                schema::conversation_members::dsl::conversation_id
+                    .eq(&conversation_id)
+                    .and(schema::conversation_members::dsl::user_id.eq(&user_id)),
+            ),
+        )
+        .execute(&conn)?)
+    })
+    .await?;
+    Ok(ResultModel::empty())
+}
+
+pub async fn leave_conversation(
+    web::Path(conversation_id): web::Path<i32>,
+    auth: AuthUser,
+    pool: web::Data<DbPool>,
+) -> Result<impl Responder, AppError> {
+    let conn = pool.get()?;
+    let self_user_id = auth.user_id;
+    web::block(move || -> Result<_, AppError> {
+        let info = joined_conversation(&conn, self_user_id, conversation_id)?;
+        Ok(conn.transaction::<_, diesel::result::Error, _>(|| {
+            diesel::delete(
+                schema::conversation_members::dsl::conversation_members.filter(
+                    schema::conversation_members::dsl::conversation_id
+                        .eq(&conversation_id)
+                        .and(schema::conversation_members::dsl::user_id.eq(&self_user_id)),
+                ),
+            )
+            .execute(&conn)?;
+            if info.owner_id == self_user_id {
+                // Hand the conversation over to the longest-standing member,
+                // or drop it entirely once nobody is left.
+                match info.members.iter().find(|member| **member != self_user_id) {
+                    Some(new_owner_id) => diesel::update(
+                        schema::conversations::dsl::conversations
+                            .filter(schema::conversations::dsl::id.eq(&conversation_id)),
+                    )
+                    .set(schema::conversations::dsl::owner_id.eq(new_owner_id))
+                    .execute(&conn)?,
+                    None => diesel::delete(
+                        schema::conversations::dsl::conversations
+                            .filter(schema::conversations::dsl::id.eq(&conversation_id)),
+                    )
+                    .execute(&conn)?,
+                };
+            }
+            Ok(())
+        })?)
+    })
+    .await?;
+    Ok(ResultModel::empty())
 }
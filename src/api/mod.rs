@@ -1,2 +1,5 @@
+pub mod attachment;
+pub mod auth;
+pub mod error;
+pub mod message;
 pub mod user;
-pub mod message;
\ No newline at end of file
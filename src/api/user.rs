@@ -1,14 +1,49 @@
 use crate::{
+    api::{
+        attachment::read_upload,
+        auth::{
+            cookie_identity, create_session, issue_tokens, refresh_token_session, refresh_tokens,
+            AuthUser, DeviceInfo, TokenSettings,
+        },
+        error::AppError,
+        message::{MessageStreamServer, TargetStreamMessage},
+    },
+    config::{PagingConfig, UploadConfig},
     model::{
-        user::{self, PasswordUpdateModel, UserInfoUpdateModel},
-        ResultModel, SearchModel,
+        message::{
+            FriendAcceptedEvent, FriendRequestEvent, GetPresence, RefreshPresence, RevokeSessions,
+            StreamEvent,
+        },
+        user::{
+            self, AvatarSizeModel, BlockInfo, FriendRequestInfo, FriendRequestList,
+            PasswordUpdateModel, PresenceInfo, PresenceStatus, PrivacyModel, PrivacyUpdateModel,
+            RefreshTokenModel, SessionInfo, UserInfoUpdateModel, Visibility,
+        },
+        PageModel, ResultModel, SearchModel,
     },
-    schema::{self, NewUser},
+    schema::{self, NewUser, PrivacyChangeset},
+    storage::Storage,
     DbPool,
 };
+use actix::Addr;
 use actix_identity::Identity;
-use actix_web::{error::BlockingError, web, Responder};
+use actix_multipart::Multipart;
+use actix_web::{error::BlockingError, http::header, web, HttpRequest, HttpResponse, Responder};
+use chrono::{NaiveDateTime, Utc};
 use diesel::prelude::*;
+use image::{imageops::FilterType, ImageFormat, ImageOutputFormat};
+use rand::Rng;
+use std::{collections::HashMap, io::Cursor, sync::Arc};
+
+const AVATAR_SIZES: &[u32] = &[64, 128, 256];
+const DEFAULT_AVATAR_SIZE: u32 = 128;
+const MAX_AVATAR_DIMENSION: u32 = 4096;
+const AVATAR_CONTENT_TYPES: &[&str] = &["image/png", "image/jpeg", "image/gif", "image/webp"];
+/// `Visibility` values as stored in the `*_visibility` columns of `users`.
+const VISIBILITY_EVERYONE: i32 = 0;
+const VISIBILITY_FRIENDS: i32 = 1;
+const VISIBILITY_NOBODY: i32 = 2;
+const MIN_PASSWORD_LENGTH: usize = 8;
 
 pub fn config(cfg: &mut web::ServiceConfig) {
     cfg.route("/profiles", web::get().to(profiles));
@@ -18,645 +53,1244 @@ pub fn config(cfg: &mut web::ServiceConfig) {
     cfg.route("/search", web::get().to(search));
     cfg.route("/login", web::post().to(login));
     cfg.route("/logout", web::post().to(logout));
+    cfg.route("/token/refresh", web::post().to(refresh_token));
+    cfg.route("/sessions", web::get().to(sessions));
+    cfg.route("/sessions", web::delete().to(revoke_other_sessions));
+    cfg.route("/sessions/{session_id}", web::delete().to(revoke_session));
     cfg.route("/register", web::post().to(register));
     cfg.route("/friends", web::get().to(friends));
+    cfg.route("/friends/requests", web::get().to(friend_requests));
+    cfg.route(
+        "/friends/requests/{request_id}/accept",
+        web::post().to(accept_friend_request),
+    );
+    cfg.route(
+        "/friends/requests/{request_id}/decline",
+        web::post().to(decline_friend_request),
+    );
+    cfg.route(
+        "/friends/requests/{request_id}",
+        web::delete().to(cancel_friend_request),
+    );
     cfg.route("/friends/{user_id}", web::post().to(add_friend));
     cfg.route("/friends/{user_id}", web::delete().to(delete_friend));
+    cfg.route("/blocks", web::get().to(blocks));
+    cfg.route("/blocks/{user_id}", web::post().to(block_user));
+    cfg.route("/blocks/{user_id}", web::delete().to(unblock_user));
+    cfg.route("/presence", web::get().to(presence));
+    cfg.route("/privacy", web::get().to(privacy));
+    cfg.route("/privacy", web::post().to(update_privacy));
+    cfg.route("/avatar", web::post().to(upload_avatar));
+    cfg.route("/avatar", web::delete().to(delete_avatar));
+    cfg.route("/avatars/{user_id}/{version}", web::get().to(avatar));
+}
+
+fn avatar_key(user_id: i32, version: &str, size: u32) -> String {
+    format!("avatar-{}-{}-{}.png", user_id, version, size)
+}
+
+/// Removes the thumbnails behind an avatar URL previously issued by `upload_avatar`.
+fn remove_avatar(storage: &dyn Storage, user_id: i32, avatar_url: &str) {
+    if let Some(version) = avatar_url.strip_prefix(&format!("/api/user/avatars/{}/", user_id)) {
+        for size in AVATAR_SIZES {
+            let _ = storage.remove(&avatar_key(user_id, version, *size));
+        }
+    }
+}
+
+/// Returns whether either user has blocked the other.
+pub fn blocked_between(conn: &PgConnection, user_id: i32, other_user_id: i32) -> QueryResult<bool> {
+    use schema::blocks::dsl;
+    dsl::blocks
+        .filter(
+            dsl::user_id
+                .eq(&user_id)
+                .and(dsl::blocked_user_id.eq(&other_user_id))
+                .or(dsl::user_id
+                    .eq(&other_user_id)
+                    .and(dsl::blocked_user_id.eq(&user_id))),
+        )
+        .select(dsl::id)
+        .first::<i32>(conn)
+        .optional()
+        .map(|block| block.is_some())
+}
+
+/// Ids of the users `user_id` is friends with.
+pub fn friend_ids(conn: &PgConnection, user_id: i32) -> QueryResult<Vec<i32>> {
+    schema::friends::dsl::friends
+        .filter(schema::friends::dsl::user_id.eq(&user_id))
+        .select(schema::friends::dsl::friend_user_id)
+        .load::<i32>(conn)
+}
+
+/// Ids of the users who have blocked `user_id`.
+fn blocker_ids(conn: &PgConnection, user_id: i32) -> QueryResult<Vec<i32>> {
+    schema::blocks::dsl::blocks
+        .filter(schema::blocks::dsl::blocked_user_id.eq(&user_id))
+        .select(schema::blocks::dsl::user_id)
+        .load::<i32>(conn)
 }
 
 pub async fn search(
     web::Query(query): web::Query<SearchModel>,
+    auth: AuthUser,
     pool: web::Data<DbPool>,
-) -> impl Responder {
-    let conn = pool.get().expect("Failed to get db connection from pool.");
-    match web::block(move || {
+    paging: web::Data<PagingConfig>,
+) -> Result<impl Responder, AppError> {
+    let conn = pool.get()?;
+    let self_user_id = auth.user_id;
+    let limit = paging.page_size(query.limit);
+    let (mut result, friend_ids) = web::block(move || -> QueryResult<_> {
+        let blockers = blocker_ids(&conn, self_user_id)?;
+        let friend_ids = friend_ids(&conn, self_user_id)?;
         use schema::users::dsl::*;
-        users
+        // Usernames match on a substring, while email and phone only match exactly and
+        // only where the caller may see them.
+        let result = users
             .filter(
                 username
-                    .like(&query.patterns)
-                    .or(email.like(&query.patterns).or(phone.like(&query.patterns))),
+                    .ilike(format!("%{}%", escape_like(&query.patterns)))
+                    .or(email.ilike(escape_like(&query.patterns)).and(
+                        email_visibility.eq(VISIBILITY_EVERYONE).or(email_visibility
+                            .eq(VISIBILITY_FRIENDS)
+                            .and(id.eq_any(&friend_ids))),
+                    ))
+                    .or(phone.eq(&query.patterns).and(
+                        phone_visibility.eq(VISIBILITY_EVERYONE).or(phone_visibility
+                            .eq(VISIBILITY_FRIENDS)
+                            .and(id.eq_any(&friend_ids))),
+                    )),
             )
-            .offset(match query.page {
-                None => 0,
-                Some(page) => ((page - 1) * 10).into(),
-            })
-            .limit(10)
-            .load::<schema::User>(&conn)
+            .filter(id.ne_all(blockers))
+            .filter(id.gt(query.after.unwrap_or(0)))
+            .order(id.asc())
+            .limit(limit + 1)
+            .load::<schema::User>(&conn)?;
+        Ok((result, friend_ids))
     })
-    .await
-    {
-        Ok(result) => ResultModel {
-            success: true,
-            data: Some(
-                result
-                    .iter()
-                    .map(|u| user::UserInfo {
-                        id: u.id,
-                        age: u.age,
-                        gender: u.gender,
-                        email: u.email.clone(),
-                        phone: u.phone.clone(),
-                        username: u.username.clone(),
-                        location: u.location.clone(),
-                        avatar: u.avatar.clone(),
-                    })
-                    .collect::<Vec<_>>(),
-            ),
-            code: 200,
-            message: None,
-        },
-        Err(BlockingError::Error(e)) => ResultModel {
-            success: false,
-            data: None,
-            code: 500,
-            message: Some(e.to_string()),
-        },
-        Err(BlockingError::Canceled) => ResultModel {
-            success: false,
-            data: None,
-            code: 500,
-            message: Some("Operation has been cancelled.".to_string()),
+    .await?;
+    let has_more = result.len() as i64 > limit;
+    result.truncate(limit as usize);
+    Ok(ResultModel::ok(PageModel {
+        next_cursor: if has_more {
+            result.last().map(|u| u.id)
+        } else {
+            None
         },
-    }
+        has_more,
+        items: result
+            .into_iter()
+            .map(|u| {
+                let is_friend = friend_ids.contains(&u.id);
+                to_user_info(u, self_user_id, is_friend)
+            })
+            .collect::<Vec<_>>(),
+    }))
 }
 
+/// Starts a login session, signing the user in with the identity cookie and also
+/// returning tokens for clients that authenticate with `Authorization: Bearer`.
 pub async fn login(
+    req: HttpRequest,
     model: web::Json<user::LoginModel>,
     identity: Identity,
     pool: web::Data<DbPool>,
-) -> impl Responder {
-    let conn = pool.get().expect("Failed to get db connection from pool.");
-    let model_username = model.username.clone();
-    let result = web::block(move || {
+    tokens: web::Data<TokenSettings>,
+) -> Result<impl Responder, AppError> {
+    let conn = pool.get()?;
+    let model = model.into_inner();
+    let device = DeviceInfo::from_request(&req, model.device_name.clone());
+    let (user_id, session_id, token) = web::block(move || -> Result<_, AppError> {
         use schema::users::dsl::*;
-        schema::users::dsl::users
-            .filter(username.eq(&model_username))
+        let entry = schema::users::dsl::users
+            .filter(username.eq(&model.username))
             .select((id, password_hash))
             .first::<(i32, String)>(&conn)
+            .optional()?;
+        match entry {
+            Some((user_id, hash)) if bcrypt::verify(&model.password, &hash)? => Ok(conn
+                .transaction(|| {
+                    let session_id = create_session(&conn, &tokens, user_id, &device)?;
+                    issue_tokens(&conn, &tokens, user_id, session_id)
+                        .map(|token| (user_id, session_id, token))
+                })?),
+            _ => Err(AppError::Unauthorized(
+                "Incorrect username or password.".to_string(),
+            )),
+        }
     })
-    .await;
-    match result {
-        Ok(entry) => match bcrypt::verify(&model.password, &entry.1) {
-            Ok(true) => {
-                identity.remember(entry.0.to_string());
-                ResultModel::<String> {
-                    success: true,
-                    data: None,
-                    code: 200,
-                    message: None,
-                }
-            }
-            _ => ResultModel {
-                success: false,
-                data: None,
-                code: 401,
-                message: Some("Incorrect username or password.".to_string()),
-            },
-        },
-        Err(BlockingError::Error(_)) => ResultModel {
-            success: false,
-            data: None,
-            code: 401,
-            message: Some("Incorrect username or password.".to_string()),
-        },
-        Err(BlockingError::Canceled) => ResultModel {
-            success: false,
-            data: None,
-            code: 500,
-            message: Some("Operation has been cancelled.".to_string()),
-        },
-    }
+    .await?;
+    identity.remember(cookie_identity(user_id, session_id));
+    Ok(ResultModel::ok(token))
 }
 
-pub async fn logout(identity: Identity) -> impl Responder {
+/// Ends the login session the request was made with, and the one the refresh token
+/// belongs to if one is given, then clears the identity cookie.
+pub async fn logout(
+    model: Option<web::Json<RefreshTokenModel>>,
+    auth: Option<AuthUser>,
+    identity: Identity,
+    pool: web::Data<DbPool>,
+    stream: web::Data<Addr<MessageStreamServer>>,
+) -> Result<impl Responder, AppError> {
     identity.forget();
-    ResultModel::<String> {
-        success: true,
-        data: None,
-        code: 200,
-        message: None,
+    let conn = pool.get()?;
+    let ended = web::block(move || -> QueryResult<_> {
+        let mut ended = auth
+            .map(|auth| (auth.user_id, auth.session_id))
+            .into_iter()
+            .collect::<Vec<_>>();
+        if let Some(model) = model {
+            ended.extend(refresh_token_session(&conn, &model.refresh_token)?);
+        }
+        for (user_id, session_id) in &ended {
+            diesel::delete(
+                schema::sessions::dsl::sessions.filter(
+                    schema::sessions::dsl::id
+                        .eq(session_id)
+                        .and(schema::sessions::dsl::user_id.eq(user_id)),
+                ),
+            )
+            .execute(&conn)?;
+        }
+        Ok(ended)
+    })
+    .await?;
+    for (user_id, session_id) in ended {
+        stream.do_send(RevokeSessions {
+            user_id,
+            login_session_ids: vec![session_id],
+        });
     }
+    Ok(ResultModel::empty())
+}
+
+pub async fn refresh_token(
+    req: HttpRequest,
+    web::Json(model): web::Json<RefreshTokenModel>,
+    pool: web::Data<DbPool>,
+    tokens: web::Data<TokenSettings>,
+) -> Result<impl Responder, AppError> {
+    let conn = pool.get()?;
+    let device = DeviceInfo::from_request(&req, None);
+    let token = web::block(move || {
+        refresh_tokens(
+            &conn,
+            &tokens,
+            &model.refresh_token,
+            device.ip_address.as_deref(),
+        )
+    })
+    .await?
+    .ok_or_else(|| AppError::Unauthorized("Invalid or expired refresh token.".to_string()))?;
+    Ok(ResultModel::ok(token))
 }
 
 pub async fn register(
+    req: HttpRequest,
     model: web::Json<user::RegisterModel>,
     identity: Identity,
     pool: web::Data<DbPool>,
-) -> impl Responder {
+    tokens: web::Data<TokenSettings>,
+) -> Result<impl Responder, AppError> {
     if model.confirm_password != model.password {
-        return ResultModel::<String> {
-            success: false,
-            data: None,
-            code: 400,
-            message: Some("Mismatch between password and confirm-password.".to_string()),
-        };
+        return Err(AppError::BadRequest(
+            "Mismatch between password and confirm-password.".to_string(),
+        ));
     }
-    let conn = pool.get().expect("Failed to get db connection from pool.");
-    let result = web::block(move || {
+    check_password_strength(&model.password)?;
+    let conn = pool.get()?;
+    let device = DeviceInfo::from_request(&req, None);
+    let (user, session_id) = web::block(move || -> Result<_, AppError> {
         use schema::users::dsl::*;
         let new_user = NewUser {
             username: &model.username,
             email: &model.email,
-            password_hash: &bcrypt::hash(&model.password, bcrypt::DEFAULT_COST)
-                .unwrap()
-                .to_string(),
+            password_hash: &bcrypt::hash(&model.password, bcrypt::DEFAULT_COST)?,
         };
-        diesel::insert_into(users)
-            .values(&new_user)
-            .get_result::<schema::User>(&conn)
+        Ok(conn.transaction(|| {
+            let user = diesel::insert_into(users)
+                .values(&new_user)
+                .get_result::<schema::User>(&conn)?;
+            create_session(&conn, &tokens, user.id, &device).map(|session_id| (user, session_id))
+        })?)
     })
-    .await;
-    match result {
-        Ok(user) => {
-            identity.remember(user.id.to_string());
-            ResultModel::<String> {
-                success: true,
-                data: None,
-                code: 200,
-                message: None,
-            }
-        }
-        Err(BlockingError::Error(err)) => ResultModel::<String> {
-            success: false,
-            data: None,
-            code: 500,
-            message: Some(err.to_string()),
-        },
-        Err(BlockingError::Canceled) => ResultModel::<String> {
-            success: false,
-            data: None,
-            code: 500,
-            message: Some("Operation has been cancelled.".to_string()),
-        },
-    }
+    .await?;
+    identity.remember(cookie_identity(user.id, session_id));
+    Ok(ResultModel::empty())
 }
 
-pub async fn profiles(identity: Identity, pool: web::Data<DbPool>) -> impl Responder {
-    let conn = pool.get().expect("Failed to get db connection from pool.");
-    match identity.identity() {
-        Some(user_id_str) => {
-            match web::block(move || {
-                use schema::users::dsl::*;
-                schema::users::dsl::users
-                    .filter(id.eq(&user_id_str.parse::<i32>().unwrap()))
-                    .first::<schema::User>(&conn)
-            })
-            .await
-            {
-                Ok(user) => ResultModel {
-                    success: true,
-                    data: Some(user::UserInfo {
-                        id: user.id,
-                        username: user.username,
-                        email: user.email,
-                        phone: user.phone,
-                        avatar: user.avatar,
-                        location: user.location,
-                        age: user.age,
-                        gender: user.gender,
-                    }),
-                    code: 200,
-                    message: None,
-                },
-                Err(_) => ResultModel {
-                    success: false,
-                    data: None,
-                    code: 401,
-                    message: Some("Not logged in.".to_string()),
-                },
-            }
-        }
-        None => ResultModel {
-            success: false,
-            data: None,
-            code: 401,
-            message: Some("Not logged in.".to_string()),
-        },
-    }
+pub async fn profiles(auth: AuthUser, pool: web::Data<DbPool>) -> Result<impl Responder, AppError> {
+    let conn = pool.get()?;
+    let self_user_id = auth.user_id;
+    let user = web::block(move || {
+        use schema::users::dsl::*;
+        schema::users::dsl::users
+            .filter(id.eq(&self_user_id))
+            .first::<schema::User>(&conn)
+            .optional()
+    })
+    .await?
+    .ok_or_else(AppError::not_logged_in)?;
+    Ok(ResultModel::ok(to_user_info(user, self_user_id, false)))
 }
 
 pub async fn profiles_with_id(
     web::Path(user_id): web::Path<i32>,
+    auth: AuthUser,
     pool: web::Data<DbPool>,
-) -> impl Responder {
-    let conn = pool.get().expect("Failed to get db connection from pool.");
-    match web::block(move || {
-        use schema::users::dsl::*;
-        schema::users::dsl::users
-            .filter(id.eq(&user_id))
+) -> Result<impl Responder, AppError> {
+    let conn = pool.get()?;
+    let self_user_id = auth.user_id;
+    let user = web::block(move || -> QueryResult<_> {
+        // Users who blocked the caller are reported as missing.
+        if blocker_ids(&conn, self_user_id)?.contains(&user_id) {
+            return Ok(None);
+        }
+        let user = schema::users::dsl::users
+            .filter(schema::users::dsl::id.eq(&user_id))
             .first::<schema::User>(&conn)
+            .optional()?;
+        let is_friend = friend_ids(&conn, self_user_id)?.contains(&user_id);
+        Ok(user.map(|user| to_user_info(user, self_user_id, is_friend)))
+    })
+    .await?
+    .ok_or_else(|| AppError::NotFound("User doesn't exists.".to_string()))?;
+    Ok(ResultModel::ok(user))
+}
+
+pub async fn friends(auth: AuthUser, pool: web::Data<DbPool>) -> Result<impl Responder, AppError> {
+    let conn = pool.get()?;
+    let self_user_id = auth.user_id;
+    let friends_result = web::block(move || {
+        schema::friends::dsl::friends
+            .filter(schema::friends::dsl::user_id.eq(&self_user_id))
+            .inner_join(
+                schema::users::dsl::users
+                    .on(schema::friends::dsl::friend_user_id.eq(schema::users::dsl::id)),
+            )
+            .select(schema::users::all_columns)
+            .load::<schema::User>(&conn)
     })
-    .await
+    .await?;
+    Ok(ResultModel::ok(
+        friends_result
+            .into_iter()
+            .map(|item| to_user_info(item, self_user_id, true))
+            .collect::<Vec<_>>(),
+    ))
+}
+
+/// Inserts both directions of a friendship and drops any pending requests between
+/// the two users. Callers run this inside a transaction.
+fn make_friends(conn: &PgConnection, user_id: i32, friend_user_id: i32) -> QueryResult<()> {
+    diesel::delete(
+        schema::friend_requests::dsl::friend_requests.filter(
+            schema::friend_requests::dsl::from_user
+                .eq(&user_id)
+                .and(schema::friend_requests::dsl::to_user.eq(&friend_user_id))
+                .or(schema::friend_requests::dsl::from_user
+                    .eq(&friend_user_id)
+                    .and(schema::friend_requests::dsl::to_user.eq(&user_id))),
+        ),
+    )
+    .execute(conn)?;
+    diesel::insert_into(schema::friends::dsl::friends)
+        .values(&vec![
+            (
+                schema::friends::dsl::user_id.eq(user_id),
+                schema::friends::dsl::friend_user_id.eq(friend_user_id),
+            ),
+            (
+                schema::friends::dsl::user_id.eq(friend_user_id),
+                schema::friends::dsl::friend_user_id.eq(user_id),
+            ),
+        ])
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Requires at least `MIN_PASSWORD_LENGTH` characters mixing letters and digits.
+fn check_password_strength(password: &str) -> Result<(), AppError> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH
+        || !password.chars().any(char::is_alphabetic)
+        || !password.chars().any(|c| c.is_ascii_digit())
     {
-        Ok(user) => ResultModel {
-            success: true,
-            data: Some(user::UserInfo {
-                id: user.id,
-                username: user.username,
-                email: user.email,
-                phone: user.phone,
-                avatar: user.avatar,
-                location: user.location,
-                age: user.age,
-                gender: user.gender,
-            }),
-            code: 200,
-            message: None,
-        },
-        Err(_) => ResultModel {
-            success: false,
-            data: None,
-            code: 404,
-            message: Some("User doesn't exists.".to_string()),
-        },
+        return Err(AppError::WeakPassword(format!(
+            "Password must be at least {} characters and contain both letters and digits.",
+            MIN_PASSWORD_LENGTH
+        )));
     }
+    Ok(())
 }
 
-pub async fn friends(identity: Identity, pool: web::Data<DbPool>) -> impl Responder {
-    let conn = pool.get().expect("Failed to get db connection from pool.");
-    match identity.identity() {
-        Some(user_id_str) => {
-            match web::block(move || {
-                let self_user_id = user_id_str.parse::<i32>().unwrap();
-                match schema::friends::dsl::friends
-                    .filter(schema::friends::dsl::user_id.eq(&self_user_id))
-                    .select(schema::friends::dsl::friend_user_id)
-                    .inner_join(
-                        schema::users::dsl::users
-                            .on(schema::friends::dsl::friend_user_id.eq(schema::users::dsl::id)),
-                    )
-                    .select((
-                        schema::users::dsl::id,
-                        schema::users::dsl::username,
-                        schema::users::dsl::email,
-                        schema::users::dsl::phone,
-                        schema::users::dsl::avatar,
-                        schema::users::dsl::gender,
-                        schema::users::dsl::age,
-                        schema::users::dsl::location,
-                    ))
-                    .load::<(i32, String, String, String, String, i32, i32, String)>(&conn)
-                {
-                    Ok(friends_result) => Ok(ResultModel {
-                        success: true,
-                        data: Some(
-                            friends_result
-                                .iter()
-                                .map(|item| {
-                                    let f = item.clone();
-                                    user::UserInfo {
-                                        id: f.0,
-                                        username: f.1,
-                                        email: f.2,
-                                        phone: f.3,
-                                        avatar: f.4,
-                                        gender: f.5,
-                                        age: f.6,
-                                        location: f.7,
-                                    }
-                                })
-                                .collect::<Vec<_>>(),
-                        ),
-                        code: 200,
-                        message: None,
-                    }),
-                    Err(_) => Ok(ResultModel {
-                        success: true,
-                        data: Some(Vec::<user::UserInfo>::default()),
-                        code: 200,
-                        message: None,
-                    }),
-                }
-            })
-            .await
-            {
-                Ok(result) => result,
-                Err(BlockingError::Error(e)) => e,
-                Err(BlockingError::Canceled) => ResultModel {
-                    success: false,
-                    data: None,
-                    code: 500,
-                    message: Some("Operation has been cancelled.".to_string()),
-                },
+/// Escapes `%`, `_` and `\` so that `pattern` matches literally in `LIKE`.
+fn escape_like(pattern: &str) -> String {
+    pattern
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+fn encode_visibility(visibility: Visibility) -> i32 {
+    match visibility {
+        Visibility::Everyone => VISIBILITY_EVERYONE,
+        Visibility::Friends => VISIBILITY_FRIENDS,
+        Visibility::Nobody => VISIBILITY_NOBODY,
+    }
+}
+
+fn decode_visibility(visibility: i32) -> Visibility {
+    match visibility {
+        VISIBILITY_EVERYONE => Visibility::Everyone,
+        VISIBILITY_FRIENDS => Visibility::Friends,
+        _ => Visibility::Nobody,
+    }
+}
+
+/// Projects `user` as seen by `self_user_id`, leaving out the fields their privacy
+/// settings hide from the caller. Users always see their own profile in full.
+pub fn to_user_info(user: schema::User, self_user_id: i32, is_friend: bool) -> user::UserInfo {
+    let visible = |visibility: i32| {
+        user.id == self_user_id
+            || match decode_visibility(visibility) {
+                Visibility::Everyone => true,
+                Visibility::Friends => is_friend,
+                Visibility::Nobody => false,
             }
-        }
-        None => ResultModel {
-            success: false,
-            data: None,
-            code: 401,
-            message: Some("Not logged in.".to_string()),
+    };
+    let email_visible = visible(user.email_visibility);
+    let phone_visible = visible(user.phone_visibility);
+    let location_visible = visible(user.location_visibility);
+    let age_visible = visible(user.age_visibility);
+    user::UserInfo {
+        id: user.id,
+        username: user.username,
+        email: if email_visible {
+            Some(user.email)
+        } else {
+            None
+        },
+        phone: if phone_visible {
+            Some(user.phone)
+        } else {
+            None
         },
+        avatar: user.avatar,
+        location: if location_visible {
+            Some(user.location)
+        } else {
+            None
+        },
+        age: if age_visible { Some(user.age) } else { None },
+        gender: user.gender,
     }
 }
 
+/// Sends a friend request, or accepts the pending request from that user if there
+/// is one.
 pub async fn add_friend(
     web::Path(user_id): web::Path<i32>,
-    identity: Identity,
+    auth: AuthUser,
     pool: web::Data<DbPool>,
-) -> impl Responder {
-    let conn = pool.get().expect("Failed to get db connection from pool.");
-
-    match identity.identity() {
-        Some(user_id_str) => {
-            let self_user_id = user_id_str.parse::<i32>().unwrap();
-            if self_user_id == user_id {
-                ResultModel::<String> {
-                    success: false,
-                    data: None,
-                    code: 400,
-                    message: Some("Cannot add yourself as friend.".to_string()),
-                }
-            } else {
-                match web::block(move || {
-                    match schema::friends::dsl::friends
-                        .filter(
-                            schema::friends::dsl::user_id
-                                .eq(&self_user_id)
-                                .and(schema::friends::dsl::friend_user_id.eq(&user_id)),
-                        )
-                        .select(schema::friends::dsl::friend_user_id)
-                        .first::<i32>(&conn)
-                    {
-                        Ok(_) => Err(ResultModel::<String> {
-                            success: false,
-                            data: None,
-                            code: 400,
-                            message: Some("Already becomes friends.".to_string()),
-                        }),
-                        Err(_) => match conn.transaction(|| {
-                            match (
-                                diesel::insert_into(schema::friends::dsl::friends)
-                                    .values((
-                                        schema::friends::dsl::user_id.eq(&self_user_id),
-                                        schema::friends::dsl::friend_user_id.eq(&user_id),
-                                    ))
-                                    .execute(&conn),
-                                diesel::insert_into(schema::friends::dsl::friends)
-                                    .values((
-                                        schema::friends::dsl::user_id.eq(&user_id),
-                                        schema::friends::dsl::friend_user_id.eq(&self_user_id),
-                                    ))
-                                    .execute(&conn),
-                            ) {
-                                (Ok(_), Ok(_)) => Ok(()),
-                                _ => Err(diesel::result::Error::RollbackTransaction),
+    stream: web::Data<Addr<MessageStreamServer>>,
+) -> Result<impl Responder, AppError> {
+    let self_user_id = auth.user_id;
+    if self_user_id == user_id {
+        return Err(AppError::BadRequest(
+            "Cannot add yourself as friend.".to_string(),
+        ));
+    }
+    let conn = pool.get()?;
+    let request_id = web::block(move || -> Result<_, AppError> {
+        if schema::friends::dsl::friends
+            .filter(
+                schema::friends::dsl::user_id
+                    .eq(&self_user_id)
+                    .and(schema::friends::dsl::friend_user_id.eq(&user_id)),
+            )
+            .select(schema::friends::dsl::friend_user_id)
+            .first::<i32>(&conn)
+            .optional()?
+            .is_some()
+        {
+            return Err(AppError::BadRequest("Already becomes friends.".to_string()));
+        }
+        if schema::users::dsl::users
+            .filter(schema::users::dsl::id.eq(&user_id))
+            .select(schema::users::dsl::id)
+            .first::<i32>(&conn)
+            .optional()?
+            .is_none()
+            || blocker_ids(&conn, self_user_id)?.contains(&user_id)
+        {
+            return Err(AppError::NotFound("User doesn't exists.".to_string()));
+        }
+        if blocked_between(&conn, self_user_id, user_id)? {
+            return Err(AppError::Forbidden(
+                "Cannot add a blocked user as friend.".to_string(),
+            ));
+        }
+        let pending = schema::friend_requests::dsl::friend_requests
+            .filter(
+                schema::friend_requests::dsl::from_user
+                    .eq(&self_user_id)
+                    .and(schema::friend_requests::dsl::to_user.eq(&user_id))
+                    .or(schema::friend_requests::dsl::from_user
+                        .eq(&user_id)
+                        .and(schema::friend_requests::dsl::to_user.eq(&self_user_id))),
+            )
+            .load::<schema::FriendRequest>(&conn)?;
+        if pending.iter().any(|item| item.from_user == self_user_id) {
+            return Err(AppError::BadRequest(
+                "Friend request already sent.".to_string(),
+            ));
+        }
+        if !pending.is_empty() {
+            conn.transaction(|| make_friends(&conn, self_user_id, user_id))?;
+            stream.do_send(TargetStreamMessage {
+                user_ids: vec![user_id],
+                message: StreamEvent::FriendAccepted(FriendAcceptedEvent {
+                    user_id: self_user_id,
+                }),
+            });
+            return Ok(None);
+        }
+        let request = diesel::insert_into(schema::friend_requests::dsl::friend_requests)
+            .values((
+                schema::friend_requests::dsl::from_user.eq(&self_user_id),
+                schema::friend_requests::dsl::to_user.eq(&user_id),
+                schema::friend_requests::dsl::create_time.eq(Utc::now().naive_utc()),
+            ))
+            .get_result::<schema::FriendRequest>(&conn)?;
+        stream.do_send(TargetStreamMessage {
+            user_ids: vec![user_id],
+            message: StreamEvent::FriendRequest(FriendRequestEvent {
+                id: request.id,
+                user_id: self_user_id,
+                create_time: request.create_time,
+            }),
+        });
+        Ok(Some(request.id))
+    })
+    .await?;
+    Ok(ResultModel {
+        success: true,
+        data: request_id,
+        code: 200,
+        message: None,
+        error_code: None,
+    })
+}
+
+pub async fn friend_requests(
+    auth: AuthUser,
+    pool: web::Data<DbPool>,
+) -> Result<impl Responder, AppError> {
+    let conn = pool.get()?;
+    let self_user_id = auth.user_id;
+    let result = web::block(move || -> QueryResult<_> {
+        let requests = schema::friend_requests::dsl::friend_requests
+            .filter(
+                schema::friend_requests::dsl::from_user
+                    .eq(&self_user_id)
+                    .or(schema::friend_requests::dsl::to_user.eq(&self_user_id)),
+            )
+            .order(schema::friend_requests::dsl::create_time.desc())
+            .load::<schema::FriendRequest>(&conn)?;
+        let friend_ids = friend_ids(&conn, self_user_id)?;
+        let mut users = schema::users::dsl::users
+            .filter(
+                schema::users::dsl::id.eq_any(
+                    requests
+                        .iter()
+                        .map(|item| {
+                            if item.from_user == self_user_id {
+                                item.to_user
+                            } else {
+                                item.from_user
                             }
-                        }) {
-                            Ok(_) => Ok(ResultModel {
-                                success: true,
-                                data: None,
-                                code: 200,
-                                message: None,
-                            }),
-                            Err(_) => Err(ResultModel {
-                                success: false,
-                                data: None,
-                                code: 500,
-                                message: Some("Failed to add friends.".to_string()),
-                            }),
-                        },
-                    }
-                })
-                .await
-                {
-                    Ok(result) => result,
-                    Err(BlockingError::Error(e)) => e,
-                    Err(BlockingError::Canceled) => ResultModel {
-                        success: false,
-                        code: 500,
-                        data: None,
-                        message: Some("Operation has been cancelled.".to_string()),
-                    },
+                        })
+                        .collect::<Vec<_>>(),
+                ),
+            )
+            .load::<schema::User>(&conn)?
+            .into_iter()
+            .map(|item| (item.id, item))
+            .collect::<HashMap<_, _>>();
+        let mut result = FriendRequestList {
+            incoming: Vec::new(),
+            outgoing: Vec::new(),
+        };
+        for item in requests {
+            let incoming = item.to_user == self_user_id;
+            let other_user_id = if incoming {
+                item.from_user
+            } else {
+                item.to_user
+            };
+            if let Some(other_user) = users.remove(&other_user_id) {
+                let info = FriendRequestInfo {
+                    id: item.id,
+                    user: to_user_info(
+                        other_user,
+                        self_user_id,
+                        friend_ids.contains(&other_user_id),
+                    ),
+                    create_time: item.create_time,
+                };
+                if incoming {
+                    result.incoming.push(info);
+                } else {
+                    result.outgoing.push(info);
                 }
             }
         }
-        None => ResultModel {
-            success: false,
-            data: None,
-            code: 401,
-            message: Some("Not logged in.".to_string()),
-        },
+        Ok(result)
+    })
+    .await?;
+    Ok(ResultModel::ok(result))
+}
+
+pub async fn accept_friend_request(
+    web::Path(request_id): web::Path<i32>,
+    auth: AuthUser,
+    pool: web::Data<DbPool>,
+    stream: web::Data<Addr<MessageStreamServer>>,
+) -> Result<impl Responder, AppError> {
+    let conn = pool.get()?;
+    let self_user_id = auth.user_id;
+    let from_user = web::block(move || -> Result<_, AppError> {
+        let request = schema::friend_requests::dsl::friend_requests
+            .filter(
+                schema::friend_requests::dsl::id
+                    .eq(&request_id)
+                    .and(schema::friend_requests::dsl::to_user.eq(&self_user_id)),
+            )
+            .first::<schema::FriendRequest>(&conn)
+            .optional()?
+            .ok_or_else(|| AppError::NotFound("Friend request doesn't exists.".to_string()))?;
+        conn.transaction(|| make_friends(&conn, self_user_id, request.from_user))?;
+        Ok(request.from_user)
+    })
+    .await?;
+    stream.do_send(TargetStreamMessage {
+        user_ids: vec![from_user],
+        message: StreamEvent::FriendAccepted(FriendAcceptedEvent {
+            user_id: self_user_id,
+        }),
+    });
+    Ok(ResultModel::empty())
+}
+
+pub async fn decline_friend_request(
+    web::Path(request_id): web::Path<i32>,
+    auth: AuthUser,
+    pool: web::Data<DbPool>,
+) -> Result<impl Responder, AppError> {
+    let conn = pool.get()?;
+    let self_user_id = auth.user_id;
+    let deleted = web::block(move || {
+        diesel::delete(
+            schema::friend_requests::dsl::friend_requests.filter(
+                schema::friend_requests::dsl::id
+                    .eq(&request_id)
+                    .and(schema::friend_requests::dsl::to_user.eq(&self_user_id)),
+            ),
+        )
+        .execute(&conn)
+    })
+    .await?;
+    if deleted == 0 {
+        return Err(AppError::NotFound(
+            "Friend request doesn't exists.".to_string(),
+        ));
+    }
+    Ok(ResultModel::empty())
+}
+
+pub async fn cancel_friend_request(
+    web::Path(request_id): web::Path<i32>,
+    auth: AuthUser,
+    pool: web::Data<DbPool>,
+) -> Result<impl Responder, AppError> {
+    let conn = pool.get()?;
+    let self_user_id = auth.user_id;
+    let deleted = web::block(move || {
+        diesel::delete(
+            schema::friend_requests::dsl::friend_requests.filter(
+                schema::friend_requests::dsl::id
+                    .eq(&request_id)
+                    .and(schema::friend_requests::dsl::from_user.eq(&self_user_id)),
+            ),
+        )
+        .execute(&conn)
+    })
+    .await?;
+    if deleted == 0 {
+        return Err(AppError::NotFound(
+            "Friend request doesn't exists.".to_string(),
+        ));
     }
+    Ok(ResultModel::empty())
 }
 
 pub async fn delete_friend(
     web::Path(user_id): web::Path<i32>,
-    identity: Identity,
+    auth: AuthUser,
     pool: web::Data<DbPool>,
-) -> impl Responder {
-    let conn = pool.get().expect("Failed to get db connection from pool.");
-
-    match identity.identity() {
-        Some(user_id_str) => {
-            let self_user_id = user_id_str.parse::<i32>().unwrap();
-            match web::block(move || {
-                match schema::friends::dsl::friends
-                    .filter(
-                        schema::friends::dsl::user_id
-                            .eq(&self_user_id)
-                            .and(schema::friends::dsl::friend_user_id.eq(&user_id)),
-                    )
-                    .select(schema::friends::dsl::friend_user_id)
-                    .first::<i32>(&conn)
-                {
-                    Ok(_) => match diesel::delete(
-                        schema::friends::dsl::friends.filter(
-                            schema::friends::dsl::user_id
-                                .eq(&self_user_id)
-                                .and(schema::friends::dsl::friend_user_id.eq(&user_id))
-                                .or(schema::friends::dsl::user_id
-                                    .eq(&user_id)
-                                    .and(schema::friends::dsl::friend_user_id.eq(&self_user_id))),
-                        ),
-                    )
-                    .execute(&conn)
-                    {
-                        Ok(_) => Ok(ResultModel {
-                            success: true,
-                            data: None,
-                            code: 200,
-                            message: None,
-                        }),
-                        Err(_) => Err(ResultModel {
-                            success: false,
-                            data: None,
-                            code: 500,
-                            message: Some("Failed to delete friends.".to_string()),
-                        }),
-                    },
-                    Err(_) => Err(ResultModel::<String> {
-                        success: false,
-                        data: None,
-                        code: 400,
-                        message: Some("Hasn't become friends.".to_string()),
-                    }),
+) -> Result<impl Responder, AppError> {
+    let conn = pool.get()?;
+    let self_user_id = auth.user_id;
+    web::block(move || -> Result<_, AppError> {
+        schema::friends::dsl::friends
+            .filter(
+                schema::friends::dsl::user_id
+                    .eq(&self_user_id)
+                    .and(schema::friends::dsl::friend_user_id.eq(&user_id)),
+            )
+            .select(schema::friends::dsl::friend_user_id)
+            .first::<i32>(&conn)
+            .optional()?
+            .ok_or_else(|| AppError::BadRequest("Hasn't become friends.".to_string()))?;
+        diesel::delete(
+            schema::friends::dsl::friends.filter(
+                schema::friends::dsl::user_id
+                    .eq(&self_user_id)
+                    .and(schema::friends::dsl::friend_user_id.eq(&user_id))
+                    .or(schema::friends::dsl::user_id
+                        .eq(&user_id)
+                        .and(schema::friends::dsl::friend_user_id.eq(&self_user_id))),
+            ),
+        )
+        .execute(&conn)?;
+        Ok(())
+    })
+    .await?;
+    Ok(ResultModel::empty())
+}
+
+pub async fn blocks(auth: AuthUser, pool: web::Data<DbPool>) -> Result<impl Responder, AppError> {
+    let conn = pool.get()?;
+    let self_user_id = auth.user_id;
+    let (result, friend_ids) = web::block(move || -> QueryResult<_> {
+        let result = schema::blocks::dsl::blocks
+            .inner_join(
+                schema::users::dsl::users
+                    .on(schema::users::dsl::id.eq(schema::blocks::dsl::blocked_user_id)),
+            )
+            .filter(schema::blocks::dsl::user_id.eq(&self_user_id))
+            .order(schema::blocks::dsl::create_time.desc())
+            .load::<(schema::Block, schema::User)>(&conn)?;
+        Ok((result, friend_ids(&conn, self_user_id)?))
+    })
+    .await?;
+    Ok(ResultModel::ok(
+        result
+            .into_iter()
+            .map(|(block, blocked_user)| {
+                let is_friend = friend_ids.contains(&blocked_user.id);
+                BlockInfo {
+                    user: to_user_info(blocked_user, self_user_id, is_friend),
+                    create_time: block.create_time,
                 }
             })
-            .await
-            {
-                Ok(result) => result,
-                Err(BlockingError::Error(e)) => e,
-                Err(BlockingError::Canceled) => ResultModel {
-                    success: false,
-                    code: 500,
-                    data: None,
-                    message: Some("Operation has been cancelled.".to_string()),
-                },
-            }
-        }
-        None => ResultModel {
-            success: false,
-            data: None,
-            code: 401,
-            message: Some("Not logged in.".to_string()),
-        },
-    }
+            .collect::<Vec<_>>(),
+    ))
 }
 
-pub async fn update_profiles(
-    web::Json(model): web::Json<UserInfoUpdateModel>,
-    identity: Identity,
+/// Blocks a user and drops any pending friend requests between the two users.
+pub async fn block_user(
+    web::Path(user_id): web::Path<i32>,
+    auth: AuthUser,
     pool: web::Data<DbPool>,
-) -> impl Responder {
-    let conn = pool.get().expect("Failed to get db connection from pool.");
-
-    match identity.identity() {
-        Some(user_id_str) => {
-            let self_user_id = user_id_str.parse::<i32>().unwrap();
-            match web::block(move || {
-                diesel::update(
-                    schema::users::dsl::users.filter(schema::users::dsl::id.eq(&self_user_id)),
-                )
-                .set((
-                    schema::users::dsl::username.eq(&model.username),
-                    schema::users::dsl::email.eq(&model.email),
-                    schema::users::dsl::phone.eq(&model.phone),
-                    schema::users::dsl::location.eq(&model.location),
-                    schema::users::dsl::age.eq(&model.age),
-                    schema::users::dsl::gender.eq(&model.gender),
-                    schema::users::dsl::avatar.eq(&model.avatar),
+) -> Result<impl Responder, AppError> {
+    let self_user_id = auth.user_id;
+    if self_user_id == user_id {
+        return Err(AppError::BadRequest("Cannot block yourself.".to_string()));
+    }
+    let conn = pool.get()?;
+    web::block(move || -> Result<_, AppError> {
+        schema::users::dsl::users
+            .filter(schema::users::dsl::id.eq(&user_id))
+            .select(schema::users::dsl::id)
+            .first::<i32>(&conn)
+            .optional()?
+            .ok_or_else(|| AppError::NotFound("User doesn't exists.".to_string()))?;
+        conn.transaction::<_, diesel::result::Error, _>(|| {
+            diesel::insert_into(schema::blocks::dsl::blocks)
+                .values((
+                    schema::blocks::dsl::user_id.eq(&self_user_id),
+                    schema::blocks::dsl::blocked_user_id.eq(&user_id),
+                    schema::blocks::dsl::create_time.eq(Utc::now().naive_utc()),
                 ))
-                .execute(&conn)
+                .on_conflict_do_nothing()
+                .execute(&conn)?;
+            diesel::delete(
+                schema::friend_requests::dsl::friend_requests.filter(
+                    schema::friend_requests::dsl::from_user
+                        .eq(&self_user_id)
+                        .and(schema::friend_requests::dsl::to_user.eq(&user_id))
+                        .or(schema::friend_requests::dsl::from_user
+                            .eq(&user_id)
+                            .and(schema::friend_requests::dsl::to_user.eq(&self_user_id))),
+                ),
+            )
+            .execute(&conn)?;
+            diesel::delete(
+                schema::friends::dsl::friends.filter(
+                    schema::friends::dsl::user_id
+                        .eq(&self_user_id)
+                        .and(schema::friends::dsl::friend_user_id.eq(&user_id))
+                        .or(schema::friends::dsl::user_id
+                            .eq(&user_id)
+                            .and(schema::friends::dsl::friend_user_id.eq(&self_user_id))),
+                ),
+            )
+            .execute(&conn)?;
+            Ok(())
+        })?;
+        Ok(())
+    })
+    .await?;
+    Ok(ResultModel::empty())
+}
+
+pub async fn unblock_user(
+    web::Path(user_id): web::Path<i32>,
+    auth: AuthUser,
+    pool: web::Data<DbPool>,
+) -> Result<impl Responder, AppError> {
+    let conn = pool.get()?;
+    let self_user_id = auth.user_id;
+    let deleted = web::block(move || {
+        diesel::delete(
+            schema::blocks::dsl::blocks.filter(
+                schema::blocks::dsl::user_id
+                    .eq(&self_user_id)
+                    .and(schema::blocks::dsl::blocked_user_id.eq(&user_id)),
+            ),
+        )
+        .execute(&conn)
+    })
+    .await?;
+    if deleted == 0 {
+        return Err(AppError::BadRequest(
+            "Hasn't blocked this user.".to_string(),
+        ));
+    }
+    Ok(ResultModel::empty())
+}
+
+pub async fn presence(
+    auth: AuthUser,
+    pool: web::Data<DbPool>,
+    stream: web::Data<Addr<MessageStreamServer>>,
+) -> Result<impl Responder, AppError> {
+    let conn = pool.get()?;
+    let self_user_id = auth.user_id;
+    let friends_result = web::block(move || {
+        schema::friends::table
+            .inner_join(
+                schema::users::table.on(schema::friends::friend_user_id.eq(schema::users::id)),
+            )
+            .filter(schema::friends::user_id.eq(self_user_id))
+            .select((
+                schema::users::id,
+                schema::users::hide_presence,
+                schema::users::last_seen,
+            ))
+            .order(schema::users::id)
+            .load::<(i32, bool, Option<NaiveDateTime>)>(&conn)
+    })
+    .await?;
+    let statuses = stream
+        .send(GetPresence {
+            user_ids: friends_result.iter().map(|item| item.0).collect(),
+        })
+        .await
+        .map_err(|e| AppError::Internal(e.to_string()))?;
+    Ok(ResultModel::ok(
+        friends_result
+            .into_iter()
+            .zip(statuses)
+            .map(|((user_id, hide_presence, last_seen), status)| {
+                if hide_presence {
+                    PresenceInfo {
+                        user_id,
+                        status: PresenceStatus::Offline,
+                        last_seen: None,
+                    }
+                } else {
+                    PresenceInfo {
+                        user_id,
+                        status,
+                        last_seen,
+                    }
+                }
             })
-            .await
-            {
-                Ok(_) => ResultModel::<String> {
-                    success: true,
-                    code: 200,
-                    data: None,
-                    message: None,
-                },
-                Err(BlockingError::Error(e)) => ResultModel {
-                    success: false,
-                    code: 500,
-                    data: None,
-                    message: Some(e.to_string()),
-                },
-                Err(BlockingError::Canceled) => ResultModel {
-                    success: false,
-                    code: 500,
-                    data: None,
-                    message: Some("Operation has been cancelled.".to_string()),
-                },
-            }
-        }
-        None => ResultModel {
-            success: false,
-            data: None,
-            code: 401,
-            message: Some("Not logged in.".to_string()),
-        },
+            .collect::<Vec<_>>(),
+    ))
+}
+
+pub async fn privacy(auth: AuthUser, pool: web::Data<DbPool>) -> Result<impl Responder, AppError> {
+    let conn = pool.get()?;
+    let self_user_id = auth.user_id;
+    let user = web::block(move || {
+        schema::users::table
+            .find(self_user_id)
+            .first::<schema::User>(&conn)
+    })
+    .await?;
+    Ok(ResultModel::ok(PrivacyModel {
+        hide_presence: user.hide_presence,
+        email: decode_visibility(user.email_visibility),
+        phone: decode_visibility(user.phone_visibility),
+        location: decode_visibility(user.location_visibility),
+        age: decode_visibility(user.age_visibility),
+    }))
+}
+
+/// Updates the given privacy settings. Changing `hidePresence` re-announces the
+/// caller's presence, so friends see them go offline as soon as they hide it.
+pub async fn update_privacy(
+    web::Json(model): web::Json<PrivacyUpdateModel>,
+    auth: AuthUser,
+    pool: web::Data<DbPool>,
+    stream: web::Data<Addr<MessageStreamServer>>,
+) -> Result<impl Responder, AppError> {
+    let self_user_id = auth.user_id;
+    let changes = PrivacyChangeset {
+        hide_presence: model.hide_presence,
+        email_visibility: model.email.map(encode_visibility),
+        phone_visibility: model.phone.map(encode_visibility),
+        location_visibility: model.location.map(encode_visibility),
+        age_visibility: model.age.map(encode_visibility),
+    };
+    if changes.hide_presence.is_none()
+        && changes.email_visibility.is_none()
+        && changes.phone_visibility.is_none()
+        && changes.location_visibility.is_none()
+        && changes.age_visibility.is_none()
+    {
+        return Err(AppError::BadRequest("No settings to update.".to_string()));
+    }
+    let conn = pool.get()?;
+    web::block(move || {
+        diesel::update(schema::users::table.find(self_user_id))
+            .set(&changes)
+            .execute(&conn)
+    })
+    .await?;
+    if model.hide_presence.is_some() {
+        stream.do_send(RefreshPresence {
+            user_id: self_user_id,
+        });
     }
+    Ok(ResultModel::empty())
+}
+
+pub async fn update_profiles(
+    web::Json(model): web::Json<UserInfoUpdateModel>,
+    auth: AuthUser,
+    pool: web::Data<DbPool>,
+) -> Result<impl Responder, AppError> {
+    let conn = pool.get()?;
+    let self_user_id = auth.user_id;
+    web::block(move || {
+        diesel::update(schema::users::dsl::users.filter(schema::users::dsl::id.eq(&self_user_id)))
+            .set((
+                schema::users::dsl::username.eq(&model.username),
+                schema::users::dsl::email.eq(&model.email),
+                schema::users::dsl::phone.eq(&model.phone),
+                schema::users::dsl::location.eq(&model.location),
+                schema::users::dsl::age.eq(&model.age),
+                schema::users::dsl::gender.eq(&model.gender),
+            ))
+            .execute(&conn)
+    })
+    .await?;
+    Ok(ResultModel::empty())
 }
 
 pub async fn update_password(
     web::Json(model): web::Json<PasswordUpdateModel>,
-    identity: Identity,
+    auth: AuthUser,
     pool: web::Data<DbPool>,
-) -> impl Responder {
+    stream: web::Data<Addr<MessageStreamServer>>,
+) -> Result<impl Responder, AppError> {
     if model.confirm_password != model.new_password {
-        return ResultModel::<String> {
-            success: false,
-            data: None,
-            code: 400,
-            message: Some("Mismatch between new-password and confirm-password.".to_string()),
-        };
+        return Err(AppError::BadRequest(
+            "Mismatch between new-password and confirm-password.".to_string(),
+        ));
     }
+    check_password_strength(&model.new_password)?;
+    let conn = pool.get()?;
+    let self_user_id = auth.user_id;
+    let self_session_id = auth.session_id;
+    let revoked = web::block(move || -> Result<_, AppError> {
+        let hash = schema::users::dsl::users
+            .filter(schema::users::dsl::id.eq(&self_user_id))
+            .select(schema::users::dsl::password_hash)
+            .first::<String>(&conn)?;
+        if !bcrypt::verify(&model.original_password, &hash)? {
+            return Err(AppError::Unauthorized("Incorrect password.".to_string()));
+        }
+        let new_hash = bcrypt::hash(&model.new_password, bcrypt::DEFAULT_COST)?;
+        Ok(conn.transaction::<_, diesel::result::Error, _>(|| {
+            diesel::update(
+                schema::users::dsl::users.filter(schema::users::dsl::id.eq(&self_user_id)),
+            )
+            .set(schema::users::dsl::password_hash.eq(&new_hash))
+            .execute(&conn)?;
+            // Whoever knew the old password is signed out everywhere else.
+            diesel::delete(
+                schema::sessions::dsl::sessions.filter(
+                    schema::sessions::dsl::user_id
+                        .eq(&self_user_id)
+                        .and(schema::sessions::dsl::id.ne(&self_session_id)),
+                ),
+            )
+            .returning(schema::sessions::dsl::id)
+            .get_results::<i32>(&conn)
+        })?)
+    })
+    .await?;
+    stream.do_send(RevokeSessions {
+        user_id: self_user_id,
+        login_session_ids: revoked,
+    });
+    Ok(ResultModel::empty())
+}
 
-    let conn = pool.get().expect("Failed to get db connection from pool.");
-
-    match identity.identity() {
-        Some(user_id_str) => {
-            let self_user_id = user_id_str.parse::<i32>().unwrap();
-            match web::block(move || {
-                match schema::users::dsl::users
-                    .filter(schema::users::dsl::id.eq(&self_user_id))
-                    .select(schema::users::dsl::password_hash)
-                    .first::<String>(&conn)
-                {
-                    Ok(hash) => match bcrypt::verify(&model.original_password, &hash) {
-                        Ok(true) => {
-                            match diesel::update(
-                                schema::users::dsl::users
-                                    .filter(schema::users::dsl::id.eq(&self_user_id)),
-                            )
-                            .set(
-                                schema::users::dsl::password_hash.eq(&bcrypt::hash(
-                                    &model.new_password,
-                                    bcrypt::DEFAULT_COST,
-                                )
-                                .unwrap()
-                                .to_string()),
-                            )
-                            .execute(&conn)
-                            {
-                                Ok(_) => Ok(ResultModel::<String> {
-                                    success: true,
-                                    data: None,
-                                    code: 200,
-                                    message: None,
-                                }),
-                                Err(e) => Err(ResultModel::<String> {
-                                    success: true,
-                                    data: None,
-                                    code: 500,
-                                    message: Some(e.to_string()),
-                                }),
-                            }
-                        }
-                        _ => Err(ResultModel {
-                            success: false,
-                            data: None,
-                            code: 401,
-                            message: Some("Incorrect password.".to_string()),
-                        }),
-                    },
-                    Err(_) => Err(ResultModel {
-                        success: false,
-                        data: None,
-                        code: 401,
-                        message: Some("Incorrect password.".to_string()),
-                    }),
-                }
+/// Lists the user's login sessions, most recently used first.
+pub async fn sessions(auth: AuthUser, pool: web::Data<DbPool>) -> Result<impl Responder, AppError> {
+    let conn = pool.get()?;
+    let self_user_id = auth.user_id;
+    let result = web::block(move || {
+        schema::sessions::dsl::sessions
+            .filter(schema::sessions::dsl::user_id.eq(&self_user_id))
+            .order(schema::sessions::dsl::last_used_time.desc())
+            .load::<schema::Session>(&conn)
+    })
+    .await?;
+    Ok(ResultModel::ok(
+        result
+            .into_iter()
+            .map(|session| SessionInfo {
+                current: session.id == auth.session_id,
+                id: session.id,
+                device_name: session.device_name,
+                ip_address: session.ip_address,
+                user_agent: session.user_agent,
+                create_time: session.create_time,
+                last_used_time: session.last_used_time,
             })
-            .await
-            {
-                Ok(result) => result,
-                Err(BlockingError::Error(e)) => e,
-                Err(BlockingError::Canceled) => ResultModel {
-                    success: false,
-                    code: 500,
-                    data: None,
-                    message: Some("Operation has been cancelled.".to_string()),
-                },
+            .collect::<Vec<_>>(),
+    ))
+}
+
+/// Signs one of the user's sessions out, closing its streams and invalidating its
+/// cookie and tokens.
+pub async fn revoke_session(
+    web::Path(session_id): web::Path<i32>,
+    auth: AuthUser,
+    pool: web::Data<DbPool>,
+    stream: web::Data<Addr<MessageStreamServer>>,
+) -> Result<impl Responder, AppError> {
+    let conn = pool.get()?;
+    let self_user_id = auth.user_id;
+    let deleted = web::block(move || {
+        diesel::delete(
+            schema::sessions::dsl::sessions.filter(
+                schema::sessions::dsl::id
+                    .eq(&session_id)
+                    .and(schema::sessions::dsl::user_id.eq(&self_user_id)),
+            ),
+        )
+        .execute(&conn)
+    })
+    .await?;
+    if deleted == 0 {
+        return Err(AppError::NotFound("Session not found.".to_string()));
+    }
+    stream.do_send(RevokeSessions {
+        user_id: self_user_id,
+        login_session_ids: vec![session_id],
+    });
+    Ok(ResultModel::empty())
+}
+
+/// Signs out every session of the user except the one making the request.
+pub async fn revoke_other_sessions(
+    auth: AuthUser,
+    pool: web::Data<DbPool>,
+    stream: web::Data<Addr<MessageStreamServer>>,
+) -> Result<impl Responder, AppError> {
+    let conn = pool.get()?;
+    let self_user_id = auth.user_id;
+    let self_session_id = auth.session_id;
+    let revoked = web::block(move || {
+        diesel::delete(
+            schema::sessions::dsl::sessions.filter(
+                schema::sessions::dsl::user_id
+                    .eq(&self_user_id)
+                    .and(schema::sessions::dsl::id.ne(&self_session_id)),
+            ),
+        )
+        .returning(schema::sessions::dsl::id)
+        .get_results::<i32>(&conn)
+    })
+    .await?;
+    stream.do_send(RevokeSessions {
+        user_id: self_user_id,
+        login_session_ids: revoked,
+    });
+    Ok(ResultModel::empty())
+}
+
+pub async fn upload_avatar(
+    mut payload: Multipart,
+    auth: AuthUser,
+    pool: web::Data<DbPool>,
+    storage: web::Data<Arc<dyn Storage>>,
+    uploads: web::Data<UploadConfig>,
+) -> Result<impl Responder, AppError> {
+    let self_user_id = auth.user_id;
+    let upload = read_upload(&mut payload, uploads.max_avatar_size, AVATAR_CONTENT_TYPES).await?;
+    let conn = pool.get()?;
+    let storage = storage.get_ref().clone();
+    let avatar_url = web::block(move || -> Result<_, AppError> {
+        let invalid_image = || AppError::BadRequest("Invalid image.".to_string());
+        let reader = image::io::Reader::new(Cursor::new(&upload.data))
+            .with_guessed_format()
+            .map_err(|_| invalid_image())?;
+        match reader.format() {
+            Some(ImageFormat::Png)
+            | Some(ImageFormat::Jpeg)
+            | Some(ImageFormat::Gif)
+            | Some(ImageFormat::WebP) => (),
+            _ => return Err(invalid_image()),
+        }
+        // Check the dimensions before decoding so oversized images are never
+        // expanded in memory.
+        match reader.into_dimensions() {
+            Ok((width, height))
+                if width <= MAX_AVATAR_DIMENSION && height <= MAX_AVATAR_DIMENSION => {}
+            Ok(_) => {
+                return Err(AppError::BadRequest(
+                    "Image dimensions are too large.".to_string(),
+                ))
             }
+            Err(_) => return Err(invalid_image()),
         }
-        None => ResultModel {
-            success: false,
-            data: None,
-            code: 401,
-            message: Some("Not logged in.".to_string()),
-        },
+        let image = image::load_from_memory(&upload.data).map_err(|_| invalid_image())?;
+
+        let version = format!("{:016x}", rand::thread_rng().gen::<u64>());
+        for size in AVATAR_SIZES {
+            let mut thumbnail = Vec::<u8>::new();
+            let saved = image
+                .resize_to_fill(*size, *size, FilterType::Lanczos3)
+                .write_to(&mut thumbnail, ImageOutputFormat::Png)
+                .map_err(|e| AppError::Internal(e.to_string()))
+                .and_then(|_| {
+                    Ok(storage.save(&avatar_key(self_user_id, &version, *size), &thumbnail)?)
+                });
+            if let Err(e) = saved {
+                for size in AVATAR_SIZES {
+                    let _ = storage.remove(&avatar_key(self_user_id, &version, *size));
+                }
+                return Err(e);
+            }
+        }
+
+        let avatar_url = format!("/api/user/avatars/{}/{}", self_user_id, version);
+        let previous = schema::users::dsl::users
+            .filter(schema::users::dsl::id.eq(&self_user_id))
+            .select(schema::users::dsl::avatar)
+            .first::<String>(&conn);
+        match diesel::update(
+            schema::users::dsl::users.filter(schema::users::dsl::id.eq(&self_user_id)),
+        )
+        .set(schema::users::dsl::avatar.eq(&avatar_url))
+        .execute(&conn)
+        {
+            Ok(_) => {
+                if let Ok(previous) = previous {
+                    remove_avatar(&*storage, self_user_id, &previous);
+                }
+                Ok(avatar_url)
+            }
+            Err(e) => {
+                remove_avatar(&*storage, self_user_id, &avatar_url);
+                Err(e.into())
+            }
+        }
+    })
+    .await?;
+    Ok(ResultModel::ok(avatar_url))
+}
+
+pub async fn delete_avatar(
+    auth: AuthUser,
+    pool: web::Data<DbPool>,
+    storage: web::Data<Arc<dyn Storage>>,
+) -> Result<impl Responder, AppError> {
+    let conn = pool.get()?;
+    let self_user_id = auth.user_id;
+    let storage = storage.get_ref().clone();
+    web::block(move || -> QueryResult<_> {
+        let previous = schema::users::dsl::users
+            .filter(schema::users::dsl::id.eq(&self_user_id))
+            .select(schema::users::dsl::avatar)
+            .first::<String>(&conn)?;
+        diesel::update(schema::users::dsl::users.filter(schema::users::dsl::id.eq(&self_user_id)))
+            .set(schema::users::dsl::avatar.eq(""))
+            .execute(&conn)?;
+        remove_avatar(&*storage, self_user_id, &previous);
+        Ok(())
+    })
+    .await?;
+    Ok(ResultModel::empty())
+}
+
+pub async fn avatar(
+    req: HttpRequest,
+    web::Path((user_id, version)): web::Path<(i32, String)>,
+    web::Query(query): web::Query<AvatarSizeModel>,
+    storage: web::Data<Arc<dyn Storage>>,
+) -> Result<HttpResponse, AppError> {
+    let size = query.size.unwrap_or(DEFAULT_AVATAR_SIZE);
+    if !AVATAR_SIZES.contains(&size) {
+        return Err(AppError::BadRequest("Unsupported avatar size.".to_string()));
+    }
+    let not_found = || AppError::NotFound("Avatar doesn't exists.".to_string());
+    if version.is_empty() || !version.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(not_found());
+    }
+
+    // Avatar URLs change on every upload, so a version can be cached forever.
+    let etag = format!("\"{}-{}\"", version, size);
+    if req
+        .headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        == Some(etag.as_str())
+    {
+        return Ok(HttpResponse::NotModified()
+            .header(header::ETAG, etag)
+            .finish());
     }
+    let storage = storage.get_ref().clone();
+    let data = match web::block(move || storage.load(&avatar_key(user_id, &version, size))).await {
+        Ok(data) => data,
+        Err(BlockingError::Error(_)) => return Err(not_found()),
+        Err(BlockingError::Canceled) => return Err(AppError::Canceled),
+    };
+    Ok(HttpResponse::Ok()
+        .content_type("image/png")
+        .header(header::ETAG, etag)
+        .header(header::CACHE_CONTROL, "public, max-age=31536000, immutable")
+        .body(data))
 }
@@ -0,0 +1,526 @@
+use rand::Rng;
+use serde::{Deserialize, Deserializer};
+use std::{
+    env, fmt,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    net::ToSocketAddrs,
+};
+
+/// Names the settings file; defaults to `config.toml` in the working directory.
+const CONFIG_FILE_VAR: &str = "CONFIG_FILE";
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+/// Environment variables named `CHAT_<SECTION>__<KEY>` override `key` in `[section]`,
+/// e.g. `CHAT_COOKIE__SECURE=true`.
+const ENV_PREFIX: &str = "CHAT_";
+/// Pre-`[message]` name of `message.recall_window`, still honored unless
+/// `CHAT_MESSAGE__RECALL_WINDOW` is set.
+const LEGACY_RECALL_WINDOW_VAR: &str = "MESSAGE_RECALL_WINDOW";
+const MIN_COOKIE_KEY_LENGTH: usize = 32;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(String, io::Error),
+    Parse(String),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "{}: {}", path, e),
+            ConfigError::Parse(message) | ConfigError::Invalid(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub cookie: CookieConfig,
+    pub database: DatabaseConfig,
+    pub uploads: UploadConfig,
+    pub paging: PagingConfig,
+    pub message: MessageConfig,
+    pub auth: AuthConfig,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Addresses to listen on; a single address may be given as a plain string.
+    #[serde(deserialize_with = "one_or_many")]
+    pub bind: Vec<String>,
+    /// Worker threads, one per CPU core when unset.
+    pub workers: Option<usize>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: vec!["0.0.0.0:8080".to_string()],
+            workers: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CookieConfig {
+    /// Base64 encoded signing key of at least 32 bytes. When unset, the key is read
+    /// from `key_file`, which is created with a random key on first run.
+    pub key: Option<String>,
+    pub key_file: String,
+    pub name: String,
+    pub secure: bool,
+    pub http_only: bool,
+    pub same_site: Option<SameSite>,
+    pub domain: Option<String>,
+    /// Lifetime in seconds; without it the cookie lasts for the browser session.
+    pub max_age: Option<i64>,
+    #[serde(skip)]
+    signing_key: Vec<u8>,
+}
+
+impl CookieConfig {
+    pub fn signing_key(&self) -> &[u8] {
+        &self.signing_key
+    }
+}
+
+impl Default for CookieConfig {
+    fn default() -> Self {
+        Self {
+            key: None,
+            key_file: "cookie.key".to_string(),
+            name: "mosad_user".to_string(),
+            secure: false,
+            http_only: true,
+            same_site: None,
+            domain: None,
+            max_age: None,
+            signing_key: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    /// Connection string; the `DATABASE_URL` environment variable takes precedence.
+    pub url: String,
+    pub pool_size: u32,
+    pub min_idle: Option<u32>,
+    /// Seconds to wait for a free connection.
+    pub connection_timeout: u64,
+    /// Seconds after which idle connections are closed, or 0 to keep them.
+    pub idle_timeout: u64,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            pool_size: 10,
+            min_idle: None,
+            connection_timeout: 30,
+            idle_timeout: 600,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct UploadConfig {
+    pub dir: String,
+    /// Largest attachment accepted, in bytes.
+    pub max_attachment_size: usize,
+    /// Largest avatar image accepted, in bytes.
+    pub max_avatar_size: usize,
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            dir: "uploads".to_string(),
+            max_attachment_size: 20 * 1024 * 1024,
+            max_avatar_size: 5 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct PagingConfig {
+    pub default_page_size: i64,
+    pub max_page_size: i64,
+    /// Messages returned by one `/sync` call.
+    pub sync_page_size: i64,
+}
+
+impl PagingConfig {
+    /// Resolves a requested page size, falling back to the default for missing or
+    /// non-positive sizes and capping it at `max_page_size`.
+    pub fn page_size(&self, limit: Option<i64>) -> i64 {
+        limit
+            .filter(|size| *size > 0)
+            .unwrap_or(self.default_page_size)
+            .min(self.max_page_size)
+    }
+}
+
+impl Default for PagingConfig {
+    fn default() -> Self {
+        Self {
+            default_page_size: 10,
+            max_page_size: 100,
+            sync_page_size: 100,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct MessageConfig {
+    /// Seconds after sending during which a message can still be recalled.
+    pub recall_window: i64,
+}
+
+impl Default for MessageConfig {
+    fn default() -> Self {
+        Self { recall_window: 120 }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Seconds an access token stays valid.
+    pub access_token_lifetime: i64,
+    /// Seconds a refresh token stays valid when unused, and a session may go unused.
+    pub refresh_token_lifetime: i64,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            access_token_lifetime: 15 * 60,
+            refresh_token_lifetime: 30 * 24 * 60 * 60,
+        }
+    }
+}
+
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+impl Config {
+    /// Reads the settings file, applies environment overrides, resolves the cookie
+    /// key and checks the result. A missing settings file is fine unless it was named
+    /// explicitly through `CONFIG_FILE`.
+    pub fn load() -> Result<Config, ConfigError> {
+        let explicit_path = env::var(CONFIG_FILE_VAR).ok();
+        let path = explicit_path
+            .clone()
+            .unwrap_or_else(|| DEFAULT_CONFIG_FILE.to_string());
+        let mut settings = match fs::read_to_string(&path) {
+            Ok(text) => text
+                .parse::<toml::Value>()
+                .map_err(|e| ConfigError::Parse(format!("{}: {}", path, e)))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && explicit_path.is_none() => {
+                toml::Value::Table(toml::value::Table::new())
+            }
+            Err(e) => return Err(ConfigError::Io(path, e)),
+        };
+        apply_env_overrides(&mut settings, env::vars())?;
+        let mut config = settings
+            .try_into::<Config>()
+            .map_err(|e| ConfigError::Parse(e.to_string()))?;
+        if let Ok(url) = env::var("DATABASE_URL") {
+            config.database.url = url;
+        }
+        if let (Ok(value), Err(_)) = (
+            env::var(LEGACY_RECALL_WINDOW_VAR),
+            env::var(format!("{}MESSAGE__RECALL_WINDOW", ENV_PREFIX)),
+        ) {
+            config.message.recall_window = value.trim().parse().map_err(|_| {
+                ConfigError::Invalid(format!(
+                    "{} must be a number of seconds.",
+                    LEGACY_RECALL_WINDOW_VAR
+                ))
+            })?;
+        }
+        config.cookie.signing_key = load_cookie_key(&config.cookie)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |message: &str| Err(ConfigError::Invalid(message.to_string()));
+
+        if self.server.bind.is_empty() {
+            return invalid("server.bind must list at least one address.");
+        }
+        for address in &self.server.bind {
+            if let Err(e) = address.to_socket_addrs() {
+                return Err(ConfigError::Invalid(format!(
+                    "server.bind: invalid address `{}`: {}",
+                    address, e
+                )));
+            }
+        }
+        if self.server.workers == Some(0) {
+            return invalid("server.workers must be positive.");
+        }
+        if self.cookie.signing_key.len() < MIN_COOKIE_KEY_LENGTH {
+            return Err(ConfigError::Invalid(format!(
+                "cookie.key must be at least {} bytes.",
+                MIN_COOKIE_KEY_LENGTH
+            )));
+        }
+        if self.cookie.name.is_empty() {
+            return invalid("cookie.name must not be empty.");
+        }
+        if self.cookie.same_site == Some(SameSite::None) && !self.cookie.secure {
+            return invalid("cookie.same_site = \"none\" requires cookie.secure.");
+        }
+        if matches!(self.cookie.max_age, Some(max_age) if max_age <= 0) {
+            return invalid("cookie.max_age must be positive.");
+        }
+        if self.database.url.is_empty() {
+            return invalid("database.url or the DATABASE_URL environment variable must be set.");
+        }
+        if self.database.pool_size == 0 {
+            return invalid("database.pool_size must be positive.");
+        }
+        if matches!(self.database.min_idle, Some(min_idle) if min_idle > self.database.pool_size) {
+            return invalid("database.min_idle must not exceed database.pool_size.");
+        }
+        if self.database.connection_timeout == 0 {
+            return invalid("database.connection_timeout must be positive.");
+        }
+        if self.uploads.dir.is_empty() {
+            return invalid("uploads.dir must not be empty.");
+        }
+        if self.uploads.max_attachment_size == 0 || self.uploads.max_avatar_size == 0 {
+            return invalid(
+                "uploads.max_attachment_size and uploads.max_avatar_size must be positive.",
+            );
+        }
+        if self.paging.default_page_size <= 0 || self.paging.sync_page_size <= 0 {
+            return invalid("paging.default_page_size and paging.sync_page_size must be positive.");
+        }
+        if self.paging.max_page_size < self.paging.default_page_size {
+            return invalid("paging.max_page_size must not be less than paging.default_page_size.");
+        }
+        if self.message.recall_window < 0 {
+            return invalid("message.recall_window must not be negative.");
+        }
+        if self.auth.access_token_lifetime <= 0 || self.auth.refresh_token_lifetime <= 0 {
+            return invalid(
+                "auth.access_token_lifetime and auth.refresh_token_lifetime must be positive.",
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Merges `CHAT_<SECTION>__<KEY>` variables among `vars` into `settings`. Values are
+/// read as TOML where the setting takes them that way, so numbers, booleans and arrays
+/// work, and as plain strings otherwise. Other software may use the prefix too, so
+/// variables that don't name a setting are skipped with a warning.
+fn apply_env_overrides(
+    settings: &mut toml::Value,
+    vars: impl Iterator<Item = (String, String)>,
+) -> Result<(), ConfigError> {
+    for (name, value) in vars {
+        let path = match name.strip_prefix(ENV_PREFIX) {
+            Some(path) => path.to_lowercase(),
+            None => continue,
+        };
+        let (section, key) = match path.find("__") {
+            Some(index) => (&path[..index], &path[index + 2..]),
+            None => {
+                log::warn!(
+                    "Ignoring {}: expected {}<SECTION>__<KEY>.",
+                    name,
+                    ENV_PREFIX
+                );
+                continue;
+            }
+        };
+        let value = match override_value(section, key, &value) {
+            Ok(value) => value,
+            Err(e) if is_unknown_field(&e) => {
+                log::warn!(
+                    "Ignoring {}: there is no setting {}.{}.",
+                    name,
+                    section,
+                    key
+                );
+                continue;
+            }
+            Err(e) => return Err(ConfigError::Invalid(format!("{}: {}", name, e))),
+        };
+        let table = settings
+            .as_table_mut()
+            .and_then(|settings| {
+                settings
+                    .entry(section)
+                    .or_insert_with(|| toml::Value::Table(toml::value::Table::new()))
+                    .as_table_mut()
+            })
+            .ok_or_else(|| {
+                ConfigError::Invalid(format!("{}: `{}` is not a section.", name, section))
+            })?;
+        table.insert(key.to_string(), value);
+    }
+    Ok(())
+}
+
+/// Reads `value` as TOML if `key` in `[section]` accepts it that way, and as a string
+/// otherwise, so e.g. a numeric cookie name stays a string.
+fn override_value(section: &str, key: &str, value: &str) -> Result<toml::Value, toml::de::Error> {
+    let parsed = format!("value = {}", value)
+        .parse::<toml::Value>()
+        .ok()
+        .and_then(|mut parsed| parsed.as_table_mut()?.remove("value"));
+    if let Some(parsed) = parsed {
+        if check_setting(section, key, parsed.clone()).is_ok() {
+            return Ok(parsed);
+        }
+    }
+    let value = toml::Value::String(value.to_string());
+    check_setting(section, key, value.clone()).map(|_| value)
+}
+
+/// Whether a settings file holding just `key = value` in `[section]` deserializes.
+fn check_setting(section: &str, key: &str, value: toml::Value) -> Result<(), toml::de::Error> {
+    let mut table = toml::value::Table::new();
+    table.insert(key.to_string(), value);
+    let mut settings = toml::value::Table::new();
+    settings.insert(section.to_string(), toml::Value::Table(table));
+    toml::Value::Table(settings)
+        .try_into::<Config>()
+        .map(|_| ())
+}
+
+/// `deny_unknown_fields` rejects sections and keys that don't exist with this error.
+fn is_unknown_field(e: &toml::de::Error) -> bool {
+    e.to_string().starts_with("unknown field")
+}
+
+/// Decodes `cookie.key`, or reads the key file, generating it on first run.
+fn load_cookie_key(cookie: &CookieConfig) -> Result<Vec<u8>, ConfigError> {
+    let encoded = match &cookie.key {
+        Some(key) => key.clone(),
+        None => match fs::read_to_string(&cookie.key_file) {
+            Ok(key) => key,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let key = base64::encode(rand::thread_rng().gen::<[u8; 32]>());
+                write_key_file(&cookie.key_file, &key)
+                    .map_err(|e| ConfigError::Io(cookie.key_file.clone(), e))?;
+                key
+            }
+            Err(e) => return Err(ConfigError::Io(cookie.key_file.clone(), e)),
+        },
+    };
+    base64::decode(encoded.trim())
+        .map_err(|e| ConfigError::Invalid(format!("cookie.key is not valid base64: {}", e)))
+}
+
+fn write_key_file(path: &str, key: &str) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(key.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overridden(vars: &[(&str, &str)]) -> Result<Config, ConfigError> {
+        let mut settings = toml::Value::Table(toml::value::Table::new());
+        apply_env_overrides(
+            &mut settings,
+            vars.iter()
+                .map(|(name, value)| (name.to_string(), value.to_string())),
+        )?;
+        settings
+            .try_into::<Config>()
+            .map_err(|e| ConfigError::Parse(e.to_string()))
+    }
+
+    #[test]
+    fn values_are_read_as_toml() {
+        let config = overridden(&[
+            ("CHAT_PAGING__MAX_PAGE_SIZE", "50"),
+            ("CHAT_COOKIE__SECURE", "true"),
+            ("CHAT_SERVER__BIND", r#"["127.0.0.1:1", "127.0.0.1:2"]"#),
+        ])
+        .unwrap();
+        assert_eq!(config.paging.max_page_size, 50);
+        assert!(config.cookie.secure);
+        assert_eq!(config.server.bind, vec!["127.0.0.1:1", "127.0.0.1:2"]);
+    }
+
+    #[test]
+    fn string_settings_fall_back_to_the_raw_value() {
+        let config = overridden(&[
+            ("CHAT_COOKIE__NAME", "123"),
+            ("CHAT_COOKIE__DOMAIN", "true"),
+            ("CHAT_DATABASE__URL", "postgres://localhost/chat"),
+        ])
+        .unwrap();
+        assert_eq!(config.cookie.name, "123");
+        assert_eq!(config.cookie.domain.as_deref(), Some("true"));
+        assert_eq!(config.database.url, "postgres://localhost/chat");
+    }
+
+    #[test]
+    fn unrelated_variables_are_skipped() {
+        let config = overridden(&[
+            ("PATH", "/usr/bin"),
+            ("CHAT_HISTORY", "1"),
+            ("CHAT_THEME__COLOR", "blue"),
+            ("CHAT_COOKIE__COLOR", "blue"),
+        ])
+        .unwrap();
+        assert_eq!(config.cookie.name, CookieConfig::default().name);
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        let result = overridden(&[("CHAT_PAGING__MAX_PAGE_SIZE", "many")]);
+        assert!(matches!(result, Err(ConfigError::Invalid(_))));
+    }
+}
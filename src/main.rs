@@ -3,47 +3,120 @@ extern crate diesel;
 extern crate chrono;
 
 mod api;
+mod config;
 mod model;
+#[allow(non_local_definitions)]
 mod schema;
+mod storage;
 
 use actix::Actor;
 use actix_identity::{CookieIdentityPolicy, IdentityService};
-use actix_web::{web, App, HttpResponse, HttpServer};
-use api::{message, user};
+use actix_web::{cookie, web, App, HttpResponse, HttpServer};
+use api::{attachment, auth, error::AppError, message, user};
+use config::{Config, CookieConfig, SameSite};
 use diesel::{r2d2, r2d2::ConnectionManager, PgConnection};
+use std::{sync::Arc, time::Duration};
+use storage::{LocalStorage, Storage};
 
 type DbPool = r2d2::Pool<ConnectionManager<PgConnection>>;
 
+fn identity_policy(settings: &CookieConfig) -> CookieIdentityPolicy {
+    let mut policy = CookieIdentityPolicy::new(settings.signing_key())
+        .name(settings.name.as_str())
+        .http_only(settings.http_only)
+        .secure(settings.secure);
+    if let Some(same_site) = settings.same_site {
+        policy = policy.same_site(match same_site {
+            SameSite::Strict => cookie::SameSite::Strict,
+            SameSite::Lax => cookie::SameSite::Lax,
+            SameSite::None => cookie::SameSite::None,
+        });
+    }
+    if let Some(domain) = &settings.domain {
+        policy = policy.domain(domain.as_str());
+    }
+    if let Some(max_age) = settings.max_age {
+        policy = policy.max_age(max_age);
+    }
+    policy
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let database_url = std::env::var("DATABASE_URL")
-        .expect("No connection string specified in environment variable DATABASE_URL.");
-    let manager = ConnectionManager::<PgConnection>::new(database_url);
+    dotenv::dotenv().ok();
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let manager = ConnectionManager::<PgConnection>::new(config.database.url.as_str());
     let pool = r2d2::Pool::builder()
+        .max_size(config.database.pool_size)
+        .min_idle(config.database.min_idle)
+        .connection_timeout(Duration::from_secs(config.database.connection_timeout))
+        .idle_timeout(match config.database.idle_timeout {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        })
         .build(manager)
         .expect("Failed to create pool.");
-    let stream = message::MessageStreamServer::new().start();
-    HttpServer::new(move || {
+    let storage: Arc<dyn Storage> = Arc::new(
+        LocalStorage::new(&config.uploads.dir).expect("Failed to initialize attachment storage."),
+    );
+    let message_settings = message::MessageSettings {
+        recall_window: chrono::Duration::seconds(config.message.recall_window),
+    };
+    let token_settings = auth::TokenSettings {
+        key: config.cookie.signing_key().to_vec(),
+        access_token_lifetime: chrono::Duration::seconds(config.auth.access_token_lifetime),
+        refresh_token_lifetime: chrono::Duration::seconds(config.auth.refresh_token_lifetime),
+    };
+    let stream = message::MessageStreamServer::new(pool.clone()).start();
+    let cookie_settings = config.cookie.clone();
+    let upload_settings = config.uploads.clone();
+    let paging_settings = config.paging.clone();
+    let mut server = HttpServer::new(move || {
         App::new()
             .data(pool.clone())
             .data(stream.clone())
-            .wrap(IdentityService::new(
-                CookieIdentityPolicy::new(&[0; 32])
-                    .name("mosad_user")
-                    .http_only(true)
-                    .secure(false),
-            ))
+            .data(storage.clone())
+            .data(message_settings.clone())
+            .data(token_settings.clone())
+            .data(upload_settings.clone())
+            .data(paging_settings.clone())
+            // Malformed bodies, query strings and paths answer in the usual result shape.
+            .app_data(
+                web::JsonConfig::default()
+                    .error_handler(|e, _| AppError::BadRequest(e.to_string()).into()),
+            )
+            .app_data(
+                web::QueryConfig::default()
+                    .error_handler(|e, _| AppError::BadRequest(e.to_string()).into()),
+            )
+            .app_data(
+                web::PathConfig::default()
+                    .error_handler(|e, _| AppError::BadRequest(e.to_string()).into()),
+            )
+            .wrap(IdentityService::new(identity_policy(&cookie_settings)))
             .service(
                 web::scope("/api")
                     .service(web::scope("/user").configure(user::config))
-                    .service(web::scope("/message").configure(message::config)),
+                    .service(web::scope("/message").configure(message::config))
+                    .service(web::scope("/attachment").configure(attachment::config)),
             )
             .route(
                 "/",
                 web::get().to(|| HttpResponse::Ok().body("Welcome to MOSAD Group 11 Backend!")),
             )
-    })
-    .bind("0.0.0.0:8080")?
-    .run()
-    .await
+    });
+    if let Some(workers) = config.server.workers {
+        server = server.workers(workers);
+    }
+    for address in &config.server.bind {
+        server = server.bind(address)?;
+    }
+    server.run().await
 }
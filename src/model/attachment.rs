@@ -0,0 +1,10 @@
+use serde::Serialize;
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AttachmentInfo {
+    pub id: i32,
+    pub file_name: String,
+    pub content_type: String,
+    pub size: i64,
+}
@@ -1,6 +1,12 @@
 use chrono::NaiveDateTime;
 use serde::{Deserialize, Serialize};
 
+use super::{
+    attachment::AttachmentInfo,
+    user::{PresenceStatus, UserInfo},
+    ErrorCode,
+};
+
 #[derive(Serialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct Message {
@@ -8,48 +14,451 @@ pub struct Message {
     pub quote_id: Option<i32>,
     pub send_time: NaiveDateTime,
     pub read_time: Option<NaiveDateTime>,
+    pub edited_time: Option<NaiveDateTime>,
+    pub recalled_time: Option<NaiveDateTime>,
     pub from_user: i32,
-    pub to_user: i32,
-    pub message_type: i32,
-    pub message: String,
+    pub to_user: Option<i32>,
+    pub conversation_id: Option<i32>,
+    #[serde(flatten)]
+    pub content: MessageContent,
+    pub attachments: Vec<AttachmentInfo>,
+    pub reactions: Vec<ReactionInfo>,
+    pub quote: Option<QuotedMessage>,
+}
+
+/// The kind of a message together with its payload, sent and returned as
+/// `messageType` and `message`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "messageType", content = "message", rename_all = "camelCase")]
+pub enum MessageContent {
+    Text(String),
+    Image(MediaPayload),
+    File(MediaPayload),
+    Location(LocationPayload),
+    Sticker(StickerPayload),
+    System(String),
+    ContactCard(ContactCardPayload),
+}
+
+/// The payload of image and file messages, whose files are the message attachments.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct MediaPayload {
+    pub caption: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct LocationPayload {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub name: Option<String>,
+    pub address: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct StickerPayload {
+    pub pack: String,
+    pub sticker: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ContactCardPayload {
+    pub user_id: i32,
+}
+
+/// A short preview of the message a reply quotes.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct QuotedMessage {
+    pub id: i32,
+    pub from_user: i32,
+    #[serde(flatten)]
+    pub content: MessageContent,
+    pub recalled: bool,
+}
+
+/// How many users reacted to a message with `emoji`, and whether the caller did.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ReactionInfo {
+    pub emoji: String,
+    pub count: i64,
+    pub reacted: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChatListModel {
+    pub before: Option<i32>,
+    pub limit: Option<i64>,
+}
+
+/// A direct chat (`user` set) or group conversation (`conversationId` set) with its
+/// latest message.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ChatSummary {
+    pub user: Option<UserInfo>,
+    pub conversation_id: Option<i32>,
+    pub conversation_name: Option<String>,
+    pub last_message: Message,
+    pub unread_count: i64,
+}
+
+/// Chats ordered by latest activity; pass `nextCursor` as `before` for the next page.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ChatList {
+    pub items: Vec<ChatSummary>,
+    pub total_unread: i64,
+    pub has_more: bool,
+    pub next_cursor: Option<i32>,
 }
 
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub struct HistoryPageModel {
-    pub page: Option<i32>,
+pub struct MessageSearchModel {
+    pub patterns: String,
+    pub user_id: Option<i32>,
+    pub conversation_id: Option<i32>,
+    pub since: Option<NaiveDateTime>,
+    pub until: Option<NaiveDateTime>,
+    pub before: Option<i32>,
+    pub limit: Option<i64>,
+}
+
+/// A search hit. `snippet` is the HTML-escaped message text around the matches, which
+/// are wrapped in `<mark>` tags.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageSearchResult {
+    pub message: Message,
+    pub snippet: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncModel {
+    pub since_id: Option<i32>,
+    pub since_time: Option<NaiveDateTime>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncResult {
+    pub messages: Vec<Message>,
+    pub has_more: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamModel {
+    pub last_id: Option<i32>,
+    pub access_token: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct SendMessageModel {
     pub quote_id: Option<i32>,
-    pub to_user: i32,
-    pub message_type: i32,
+    pub to_user: Option<i32>,
+    pub conversation_id: Option<i32>,
+    #[serde(flatten)]
+    pub content: MessageContent,
+    #[serde(default)]
+    pub attachments: Vec<i32>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EditMessageModel {
     pub message: String,
 }
 
-#[derive(actix::Message, Debug, Serialize)]
+/// A previous body of an edited message, valid from `create_time` until the next
+/// revision or the current body replaced it.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageRevisionInfo {
+    pub message: String,
+    pub create_time: NaiveDateTime,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateConversationModel {
+    pub name: String,
+    pub members: Vec<i32>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ConversationInfo {
+    pub id: i32,
+    pub name: String,
+    pub owner_id: i32,
+    pub create_time: NaiveDateTime,
+    pub members: Vec<i32>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReadMessageModel {
+    pub message_id: i32,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReadConversationModel {
+    pub user_id: Option<i32>,
+    pub conversation_id: Option<i32>,
+    pub up_to_id: i32,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TypingModel {
+    pub to_user: Option<i32>,
+    pub conversation_id: Option<i32>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PresenceModel {
+    pub status: PresenceStatus,
+}
+
+/// A frame sent by the client over `/stream`.
+#[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
+pub struct StreamRequest {
+    pub request_id: Option<serde_json::Value>,
+    #[serde(flatten)]
+    pub action: StreamAction,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum StreamAction {
+    Send(SendMessageModel),
+    Read(ReadMessageModel),
+    TypingStart(TypingModel),
+    TypingStop(TypingModel),
+    Presence(PresenceModel),
+    Ping,
+}
+
+/// A frame sent by the server over `/stream`.
+#[derive(actix::Message, Debug, Serialize, Clone)]
+#[serde(tag = "type", rename_all = "camelCase")]
 #[rtype(result = "()")]
+pub enum StreamEvent {
+    Message(StreamMessage),
+    Synced(SyncedEvent),
+    Read(ReadEvent),
+    Edited(EditedEvent),
+    Recalled(RecalledEvent),
+    Reaction(ReactionEvent),
+    Typing(TypingEvent),
+    FriendRequest(FriendRequestEvent),
+    FriendAccepted(FriendAcceptedEvent),
+    Presence(PresenceEvent),
+    /// The login session behind the stream was revoked; the server closes it next.
+    SessionRevoked,
+    Ack(StreamAck),
+    Error(StreamError),
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
 pub struct StreamMessage {
     pub id: i32,
     pub user_id: i32,
+    pub conversation_id: Option<i32>,
     pub quote_id: Option<i32>,
     pub send_time: NaiveDateTime,
-    pub message_type: i32,
+    /// Set on messages replayed after being edited or recalled, which a client that
+    /// was offline hasn't seen the `edited` or `recalled` frame for.
+    pub edited_time: Option<NaiveDateTime>,
+    pub recalled_time: Option<NaiveDateTime>,
+    #[serde(flatten)]
+    pub content: MessageContent,
+    pub attachments: Vec<AttachmentInfo>,
+}
+
+/// Marks the end of the replay requested with `lastId`; live messages follow.
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncedEvent {
+    pub last_id: Option<i32>,
+    /// More messages were missed than are replayed; fetch them from `/sync` with
+    /// `sinceId` set to `lastId`.
+    pub has_more: bool,
+}
+
+/// Tells a sender that `user_id` has read some of their messages.
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ReadEvent {
+    pub user_id: i32,
+    pub conversation_id: Option<i32>,
+    pub message_ids: Vec<i32>,
+    pub read_time: NaiveDateTime,
+}
+
+/// Tells the other participants that `user_id` has edited one of their messages.
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct EditedEvent {
+    pub id: i32,
+    pub user_id: i32,
+    pub conversation_id: Option<i32>,
     pub message: String,
+    pub edited_time: NaiveDateTime,
+}
+
+/// Tells the other participants that `user_id` has recalled one of their messages.
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RecalledEvent {
+    pub id: i32,
+    pub user_id: i32,
+    pub conversation_id: Option<i32>,
+    pub recalled_time: NaiveDateTime,
+}
+
+/// Tells the participants that `user_id` added or removed a reaction.
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ReactionEvent {
+    pub message_id: i32,
+    pub user_id: i32,
+    pub conversation_id: Option<i32>,
+    pub emoji: String,
+    pub added: bool,
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TypingEvent {
+    pub user_id: i32,
+    pub conversation_id: Option<i32>,
+    pub typing: bool,
+}
+
+/// Tells a user that `user_id` has sent them a friend request.
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct FriendRequestEvent {
+    pub id: i32,
+    pub user_id: i32,
+    pub create_time: NaiveDateTime,
+}
+
+/// Tells a user that `user_id` has accepted their friend request.
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct FriendAcceptedEvent {
+    pub user_id: i32,
+}
+
+/// Tells a user that the presence of their friend `user_id` has changed.
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PresenceEvent {
+    pub user_id: i32,
+    pub status: PresenceStatus,
+    pub last_seen: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamAck {
+    pub request_id: Option<serde_json::Value>,
+    pub data: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamError {
+    pub request_id: Option<serde_json::Value>,
+    pub code: u16,
+    pub message: String,
+    pub error_code: ErrorCode,
 }
 
 #[derive(actix::Message)]
-#[rtype(result = "()")]
+#[rtype(result = "usize")]
 pub struct Connect {
     pub user_id: i32,
-    pub addr: actix::Recipient<StreamMessage>,
+    /// The login session the stream was opened with.
+    pub login_session_id: i32,
+    pub addr: actix::Recipient<StreamEvent>,
 }
 
 #[derive(actix::Message)]
 #[rtype(result = "()")]
 pub struct Disconnect {
+    pub session_id: usize,
+    pub user_id: i32,
+}
+
+/// Closes the streams of `user_id` opened with any of the revoked login sessions
+/// `login_session_ids`.
+#[derive(actix::Message)]
+#[rtype(result = "()")]
+pub struct RevokeSessions {
+    pub user_id: i32,
+    pub login_session_ids: Vec<i32>,
+}
+
+/// Marks a session as away, or back online.
+#[derive(actix::Message)]
+#[rtype(result = "()")]
+pub struct SetAway {
+    pub session_id: usize,
+    pub user_id: i32,
+    pub away: bool,
+}
+
+/// Re-announces the presence of `user_id` to their friends after their privacy
+/// setting has changed.
+#[derive(actix::Message)]
+#[rtype(result = "()")]
+pub struct RefreshPresence {
+    pub user_id: i32,
+}
+
+/// Looks up the live status of each of `user_ids`, in the same order.
+#[derive(actix::Message)]
+#[rtype(result = "Vec<PresenceStatus>")]
+pub struct GetPresence {
+    pub user_ids: Vec<i32>,
+}
+
+/// The chat a typing notification is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TypingTarget {
+    User(i32),
+    Conversation(i32),
+}
+
+/// Tells `recipients` that `user_id` is typing, until a `StopTyping` or the
+/// indicator expires.
+#[derive(actix::Message)]
+#[rtype(result = "()")]
+pub struct StartTyping {
+    pub user_id: i32,
+    pub target: TypingTarget,
+    pub recipients: Vec<i32>,
+}
+
+#[derive(actix::Message)]
+#[rtype(result = "()")]
+pub struct StopTyping {
     pub user_id: i32,
+    pub target: TypingTarget,
 }
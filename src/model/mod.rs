@@ -1,23 +1,97 @@
+pub mod attachment;
 pub mod message;
 pub mod user;
 
 use actix_web::{http::StatusCode, HttpResponse, Responder};
-use serde::{Serialize, Deserialize};
+use serde::{Deserialize, Serialize};
 use std::future::{ready, Ready};
 
 #[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
 pub struct ResultModel<T: Serialize> {
     pub success: bool,
     pub code: u16,
     pub data: Option<T>,
     pub message: Option<String>,
+    /// Why the request failed; `None` on success.
+    pub error_code: Option<ErrorCode>,
+}
+
+/// Stable reason a request failed. Unlike `message`, these never change wording,
+/// so clients can branch on them.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum ErrorCode {
+    /// The request is malformed or breaks a rule.
+    BadRequest,
+    WeakPassword,
+    /// No valid credential came with the request, or its session was revoked.
+    NotLoggedIn,
+    /// A password or refresh token was rejected.
+    InvalidCredentials,
+    Forbidden,
+    NotFound,
+    UsernameTaken,
+    EmailTaken,
+    /// The request clashes with something that already exists.
+    Conflict,
+    PayloadTooLarge,
+    UnsupportedMediaType,
+    /// The server is too busy to answer; retry later.
+    Unavailable,
+    Internal,
+}
+
+impl<T: Serialize> ResultModel<T> {
+    /// A successful response carrying `data`.
+    pub fn ok(data: T) -> Self {
+        Self {
+            success: true,
+            code: 200,
+            data: Some(data),
+            message: None,
+            error_code: None,
+        }
+    }
+}
+
+impl ResultModel<()> {
+    /// A successful response without data.
+    pub fn empty() -> Self {
+        Self {
+            success: true,
+            code: 200,
+            data: None,
+            message: None,
+            error_code: None,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct SearchModel {
     pub patterns: String,
-    pub page: Option<i32>,
+    pub after: Option<i32>,
+    pub limit: Option<i64>,
+}
+
+/// Pages through items ordered by id. `before` takes precedence over `after`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CursorModel {
+    pub before: Option<i32>,
+    pub after: Option<i32>,
+    pub limit: Option<i64>,
+}
+
+/// One page of results; pass `nextCursor` back to continue in the same direction.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PageModel<T: Serialize> {
+    pub items: Vec<T>,
+    pub has_more: bool,
+    pub next_cursor: Option<i32>,
 }
 
 impl<T: Serialize> Responder for ResultModel<T> {
@@ -37,14 +111,14 @@ impl<T: Serialize> Responder for ResultModel<T> {
                     .content_type("application/json")
                     .body(format!(
                         "{{\"success\":false,code:500,message:\"{}\"}}",
-                        err.to_string()
+                        err
                     ))),
             },
             Err(err) => Ok(HttpResponse::InternalServerError()
                 .content_type("application/json")
                 .body(format!(
                     "{{\"success\":false,code:500,message:\"{}\"}}",
-                    err.to_string()
+                    err
                 ))),
         })
     }
@@ -1,15 +1,17 @@
+use chrono::NaiveDateTime;
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Debug)]
 #[serde(rename_all = "camelCase")]
+/// A user's profile. Fields their privacy settings hide from the caller are `None`.
 pub struct UserInfo {
     pub id: i32,
     pub username: String,
-    pub email: String,
-    pub phone: String,
+    pub email: Option<String>,
+    pub phone: Option<String>,
     pub avatar: String,
-    pub location: String,
-    pub age: i32,
+    pub location: Option<String>,
+    pub age: Option<i32>,
     pub gender: i32,
 }
 
@@ -18,6 +20,27 @@ pub struct UserInfo {
 pub struct LoginModel {
     pub username: String,
     pub password: String,
+    /// Shown in the session list, e.g. "Alice's phone".
+    pub device_name: Option<String>,
+}
+
+/// Tokens for clients that can't keep the identity cookie. Send the access token as
+/// `Authorization: Bearer <accessToken>` and trade the refresh token for new tokens
+/// before it expires.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TokenModel {
+    pub access_token: String,
+    pub token_type: String,
+    /// Seconds until the access token expires.
+    pub expires_in: i64,
+    pub refresh_token: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RefreshTokenModel {
+    pub refresh_token: String,
 }
 
 #[derive(Deserialize)]
@@ -35,7 +58,6 @@ pub struct UserInfoUpdateModel {
     pub username: String,
     pub email: String,
     pub phone: String,
-    pub avatar: String,
     pub location: String,
     pub age: i32,
     pub gender: i32,
@@ -48,3 +70,92 @@ pub struct PasswordUpdateModel {
     pub new_password: String,
     pub confirm_password: String,
 }
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AvatarSizeModel {
+    pub size: Option<u32>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FriendRequestInfo {
+    pub id: i32,
+    pub user: UserInfo,
+    pub create_time: NaiveDateTime,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FriendRequestList {
+    pub incoming: Vec<FriendRequestInfo>,
+    pub outgoing: Vec<FriendRequestInfo>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BlockInfo {
+    pub user: UserInfo,
+    pub create_time: NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum PresenceStatus {
+    Online,
+    Away,
+    Offline,
+}
+
+/// A friend's presence. Friends hiding their presence always appear offline with no
+/// `last_seen`.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PresenceInfo {
+    pub user_id: i32,
+    pub status: PresenceStatus,
+    pub last_seen: Option<NaiveDateTime>,
+}
+
+/// Who may see a profile field besides its owner.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum Visibility {
+    Everyone,
+    Friends,
+    Nobody,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PrivacyModel {
+    pub hide_presence: bool,
+    pub email: Visibility,
+    pub phone: Visibility,
+    pub location: Visibility,
+    pub age: Visibility,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PrivacyUpdateModel {
+    pub hide_presence: Option<bool>,
+    pub email: Option<Visibility>,
+    pub phone: Option<Visibility>,
+    pub location: Option<Visibility>,
+    pub age: Option<Visibility>,
+}
+
+/// A device or browser signed in as the user.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionInfo {
+    pub id: i32,
+    pub device_name: Option<String>,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub create_time: NaiveDateTime,
+    pub last_used_time: NaiveDateTime,
+    /// Whether this is the session making the request.
+    pub current: bool,
+}
@@ -12,19 +12,58 @@ table! {
         age -> Integer,
         gender -> Integer,
         password_hash -> Text,
+        last_seen -> Nullable<Timestamp>,
+        hide_presence -> Bool,
+        email_visibility -> Integer,
+        phone_visibility -> Integer,
+        location_visibility -> Integer,
+        age_visibility -> Integer,
     }
 }
 
+// `messages` also has a generated `search_vector` tsvector column, which is only
+// queried through raw SQL by the message search.
 table! {
     messages {
         id -> Integer,
         from_user -> Integer,
-        to_user -> Integer,
+        to_user -> Nullable<Integer>,
+        conversation_id -> Nullable<Integer>,
         quote_id -> Nullable<Integer>,
         message -> Text,
         message_type -> Integer,
         send_time -> Timestamp,
         read_time -> Nullable<Timestamp>,
+        edited_time -> Nullable<Timestamp>,
+        recalled_time -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    message_deletions {
+        id -> Integer,
+        message_id -> Integer,
+        user_id -> Integer,
+        delete_time -> Timestamp,
+    }
+}
+
+table! {
+    message_reactions {
+        id -> Integer,
+        message_id -> Integer,
+        user_id -> Integer,
+        emoji -> Text,
+        create_time -> Timestamp,
+    }
+}
+
+table! {
+    message_revisions {
+        id -> Integer,
+        message_id -> Integer,
+        message -> Text,
+        create_time -> Timestamp,
     }
 }
 
@@ -36,7 +75,94 @@ table! {
     }
 }
 
-allow_tables_to_appear_in_same_query!(users, friends, messages);
+table! {
+    conversations {
+        id -> Integer,
+        name -> Text,
+        owner_id -> Integer,
+        create_time -> Timestamp,
+    }
+}
+
+table! {
+    conversation_members {
+        id -> Integer,
+        conversation_id -> Integer,
+        user_id -> Integer,
+        join_time -> Timestamp,
+        last_read_id -> Nullable<Integer>,
+    }
+}
+
+table! {
+    attachments {
+        id -> Integer,
+        message_id -> Nullable<Integer>,
+        uploader_id -> Integer,
+        file_name -> Text,
+        content_type -> Text,
+        size -> BigInt,
+        storage_key -> Text,
+        upload_time -> Timestamp,
+    }
+}
+
+table! {
+    friend_requests {
+        id -> Integer,
+        from_user -> Integer,
+        to_user -> Integer,
+        create_time -> Timestamp,
+    }
+}
+
+table! {
+    blocks {
+        id -> Integer,
+        user_id -> Integer,
+        blocked_user_id -> Integer,
+        create_time -> Timestamp,
+    }
+}
+
+table! {
+    refresh_tokens {
+        id -> Integer,
+        user_id -> Integer,
+        token_hash -> Text,
+        create_time -> Timestamp,
+        expire_time -> Timestamp,
+        session_id -> Integer,
+    }
+}
+
+table! {
+    sessions {
+        id -> Integer,
+        user_id -> Integer,
+        device_name -> Nullable<Text>,
+        ip_address -> Nullable<Text>,
+        user_agent -> Nullable<Text>,
+        create_time -> Timestamp,
+        last_used_time -> Timestamp,
+    }
+}
+
+allow_tables_to_appear_in_same_query!(
+    users,
+    friends,
+    messages,
+    conversations,
+    conversation_members,
+    attachments,
+    friend_requests,
+    blocks,
+    message_revisions,
+    message_deletions,
+    message_reactions,
+    refresh_tokens,
+    sessions
+);
 
 #[derive(Queryable, Debug, Identifiable, Clone)]
 #[table_name = "users"]
@@ -51,20 +177,71 @@ pub struct User {
     pub age: i32,
     pub gender: i32,
     pub password_hash: String,
+    pub last_seen: Option<NaiveDateTime>,
+    pub hide_presence: bool,
+    pub email_visibility: i32,
+    pub phone_visibility: i32,
+    pub location_visibility: i32,
+    pub age_visibility: i32,
 }
 
-#[derive(Queryable, Debug, Identifiable, Clone)]
+#[derive(Queryable, QueryableByName, Debug, Identifiable, Clone)]
 #[table_name = "messages"]
 #[primary_key(id)]
 pub struct Message {
     pub id: i32,
     pub from_user: i32,
-    pub to_user: i32,
+    pub to_user: Option<i32>,
+    pub conversation_id: Option<i32>,
     pub quote_id: Option<i32>,
     pub message: String,
     pub message_type: i32,
     pub send_time: NaiveDateTime,
     pub read_time: Option<NaiveDateTime>,
+    pub edited_time: Option<NaiveDateTime>,
+    pub recalled_time: Option<NaiveDateTime>,
+}
+
+/// Unread messages of one chat: direct chats have `peer_id` set, group
+/// conversations `conversation_id`.
+#[derive(QueryableByName, Debug)]
+pub struct UnreadCount {
+    #[sql_type = "diesel::sql_types::Nullable<diesel::sql_types::Integer>"]
+    pub peer_id: Option<i32>,
+    #[sql_type = "diesel::sql_types::Nullable<diesel::sql_types::Integer>"]
+    pub conversation_id: Option<i32>,
+    #[sql_type = "diesel::sql_types::BigInt"]
+    pub unread: i64,
+}
+
+/// A message matched by the full-text search, with its highlighted snippet.
+#[derive(QueryableByName, Debug)]
+pub struct MessageSearchHit {
+    #[diesel(embed)]
+    pub message: Message,
+    #[sql_type = "diesel::sql_types::Text"]
+    pub snippet: String,
+}
+
+#[derive(Queryable, Debug, Identifiable, Clone)]
+#[table_name = "message_revisions"]
+#[primary_key(id)]
+pub struct MessageRevision {
+    pub id: i32,
+    pub message_id: i32,
+    pub message: String,
+    pub create_time: NaiveDateTime,
+}
+
+#[derive(Queryable, Debug, Identifiable, Clone)]
+#[table_name = "message_reactions"]
+#[primary_key(id)]
+pub struct MessageReaction {
+    pub id: i32,
+    pub message_id: i32,
+    pub user_id: i32,
+    pub emoji: String,
+    pub create_time: NaiveDateTime,
 }
 
 #[derive(Queryable, Debug, Identifiable, Clone)]
@@ -76,6 +253,61 @@ pub struct Friend {
     pub friend_user_id: i32,
 }
 
+#[derive(Queryable, Debug, Identifiable, Clone)]
+#[table_name = "friend_requests"]
+#[primary_key(id)]
+pub struct FriendRequest {
+    pub id: i32,
+    pub from_user: i32,
+    pub to_user: i32,
+    pub create_time: NaiveDateTime,
+}
+
+#[derive(Queryable, Debug, Identifiable, Clone)]
+#[table_name = "blocks"]
+#[primary_key(id)]
+pub struct Block {
+    pub id: i32,
+    pub user_id: i32,
+    pub blocked_user_id: i32,
+    pub create_time: NaiveDateTime,
+}
+
+#[derive(Queryable, Debug, Identifiable, Clone)]
+#[table_name = "conversations"]
+#[primary_key(id)]
+pub struct Conversation {
+    pub id: i32,
+    pub name: String,
+    pub owner_id: i32,
+    pub create_time: NaiveDateTime,
+}
+
+#[derive(Queryable, Debug, Identifiable, Clone)]
+#[table_name = "conversation_members"]
+#[primary_key(id)]
+pub struct ConversationMember {
+    pub id: i32,
+    pub conversation_id: i32,
+    pub user_id: i32,
+    pub join_time: NaiveDateTime,
+    pub last_read_id: Option<i32>,
+}
+
+#[derive(Queryable, Debug, Identifiable, Clone)]
+#[table_name = "attachments"]
+#[primary_key(id)]
+pub struct Attachment {
+    pub id: i32,
+    pub message_id: Option<i32>,
+    pub uploader_id: i32,
+    pub file_name: String,
+    pub content_type: String,
+    pub size: i64,
+    pub storage_key: String,
+    pub upload_time: NaiveDateTime,
+}
+
 #[derive(Insertable)]
 #[table_name = "users"]
 pub struct NewUser<'a> {
@@ -83,3 +315,69 @@ pub struct NewUser<'a> {
     pub email: &'a str,
     pub password_hash: &'a str,
 }
+
+/// Privacy settings to change; `None` fields are left as they are.
+#[derive(AsChangeset)]
+#[table_name = "users"]
+pub struct PrivacyChangeset {
+    pub hide_presence: Option<bool>,
+    pub email_visibility: Option<i32>,
+    pub phone_visibility: Option<i32>,
+    pub location_visibility: Option<i32>,
+    pub age_visibility: Option<i32>,
+}
+
+#[derive(Insertable)]
+#[table_name = "conversation_members"]
+pub struct NewConversationMember {
+    pub conversation_id: i32,
+    pub user_id: i32,
+    pub join_time: NaiveDateTime,
+    /// Messages sent before joining don't count as unread.
+    pub last_read_id: Option<i32>,
+}
+
+#[derive(Insertable)]
+#[table_name = "attachments"]
+pub struct NewAttachment<'a> {
+    pub uploader_id: i32,
+    pub file_name: &'a str,
+    pub content_type: &'a str,
+    pub size: i64,
+    pub storage_key: &'a str,
+    pub upload_time: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "refresh_tokens"]
+pub struct NewRefreshToken<'a> {
+    pub user_id: i32,
+    pub token_hash: &'a str,
+    pub create_time: NaiveDateTime,
+    pub expire_time: NaiveDateTime,
+    pub session_id: i32,
+}
+
+#[derive(Queryable, Debug, Identifiable, Clone)]
+#[table_name = "sessions"]
+#[primary_key(id)]
+pub struct Session {
+    pub id: i32,
+    pub user_id: i32,
+    pub device_name: Option<String>,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub create_time: NaiveDateTime,
+    pub last_used_time: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "sessions"]
+pub struct NewSession<'a> {
+    pub user_id: i32,
+    pub device_name: Option<&'a str>,
+    pub ip_address: Option<&'a str>,
+    pub user_agent: Option<&'a str>,
+    pub create_time: NaiveDateTime,
+    pub last_used_time: NaiveDateTime,
+}
@@ -0,0 +1,49 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Where uploaded files are kept. Keys are generated by the server and never
+/// contain path separators.
+pub trait Storage: Send + Sync {
+    fn save(&self, key: &str, data: &[u8]) -> io::Result<()>;
+    fn load(&self, key: &str) -> io::Result<Vec<u8>>;
+    fn remove(&self, key: &str) -> io::Result<()>;
+}
+
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new<P: AsRef<Path>>(root: P) -> io::Result<Self> {
+        fs::create_dir_all(root.as_ref())?;
+        Ok(Self {
+            root: root.as_ref().to_path_buf(),
+        })
+    }
+
+    fn path(&self, key: &str) -> io::Result<PathBuf> {
+        if key.is_empty() || key.contains(&['/', '\\'][..]) || key.starts_with('.') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid storage key.",
+            ));
+        }
+        Ok(self.root.join(key))
+    }
+}
+
+impl Storage for LocalStorage {
+    fn save(&self, key: &str, data: &[u8]) -> io::Result<()> {
+        fs::write(self.path(key)?, data)
+    }
+
+    fn load(&self, key: &str) -> io::Result<Vec<u8>> {
+        fs::read(self.path(key)?)
+    }
+
+    fn remove(&self, key: &str) -> io::Result<()> {
+        fs::remove_file(self.path(key)?)
+    }
+}